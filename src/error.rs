@@ -0,0 +1,230 @@
+use std::fmt;
+
+// failures of the market, the code is stable and prefixes the panic message: "[E007] invalid badge"
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarketError {
+  WrongNftResource = 1,
+  WrongBadgeResource = 2,
+  WrongReceiptResource = 3,
+  WrongCurrency = 4,
+  SingleItemExpected = 5,
+  NegativeCost = 6,
+  InvalidBadge = 7,
+  NotListed = 8,
+  ListingClosed = 9,
+  NothingToCollect = 10,
+  InsufficientPayment = 11,
+  SwapNotListed = 12,
+  ItemNotWanted = 13,
+  NegativeSweetener = 14,
+  BundleNotListed = 15,
+  EmptyBundle = 16,
+  RentalNotListed = 17,
+  NotARental = 18,
+  AlreadyRented = 19,
+  RentalOngoing = 20,
+  InvalidDuration = 21,
+  InvalidAmount = 22,
+  NegativeInterest = 23,
+  LoanOfferNotFound = 24,
+  LoanAlreadyTaken = 25,
+  LoanNotTaken = 26,
+  LoanDefaulted = 27,
+  LoanNotDefaulted = 28,
+  AuctionNotListed = 29,
+  InvalidSchedule = 30,
+  BidPhaseOver = 31,
+  NotInRevealPhase = 32,
+  CommitmentMismatch = 33,
+  DepositTooLow = 34,
+  RevealNotOver = 35,
+  AlreadySettled = 36,
+  InvalidReceipt = 37,
+  NothingToClaim = 38,
+  AuctionHasBids = 39,
+  AuctionEnded = 40,
+  AuctionNotEnded = 41,
+  BidTooLow = 42,
+  NoBidToSettle = 43,
+  NoBuyNowPrice = 44,
+  InvalidBuyNowPrice = 45,
+  InvalidExtension = 46,
+  PoolNotFound = 47,
+  InvalidSpotPrice = 48,
+  NegativeSpread = 49,
+  InvalidCurve = 50,
+  NftNotInPool = 51,
+  PoolOutOfCurrency = 52,
+  PoolPriceExhausted = 53,
+  InvalidWindow = 54,
+  BidNotFound = 55,
+  UnknownTraitField = 56,
+  TraitMismatch = 57,
+  ListingSold = 58,
+  ListingCancelled = 59,
+  ListingExpired = 60,
+  FixedPriceOnly = 61,
+  CannotRelist = 62,
+  NotLiveYet = 63,
+  FractionNotFound = 64,
+  BoughtOut = 65,
+  PartialShares = 66,
+  LayawayNotOffered = 67,
+  LayawayOngoing = 68,
+  LayawayNotFound = 69,
+  LayawayDefaulted = 70,
+  LayawayNotDefaulted = 71,
+  InvalidRate = 72,
+  RaffleNotListed = 73,
+  RaffleEnded = 74,
+  RaffleSoldOut = 75,
+  RaffleNotOver = 76,
+  RaffleHasTickets = 77,
+  NegativeRewardRate = 78,
+  StakingDisabled = 79,
+  WrongStakeToken = 80,
+  EscrowNotFound = 81,
+  EscrowOngoing = 82,
+  EscrowDisputed = 83,
+  EscrowNotDisputed = 84,
+  EscrowNotOver = 85,
+  NotEscrowParty = 86,
+  Migrated = 87,
+  WindingDown = 88,
+  NotWindingDown = 89,
+  Flagged = 90,
+  NotFlagged = 91,
+  BuyNowOutbid = 92,
+  MarketCurrencyOnly = 93,
+  EscrowedListing = 94
+}
+
+impl MarketError {
+  pub fn code(&self) -> u16 {
+    *self as u16
+  }
+
+  pub fn message(&self) -> &'static str {
+    match self {
+      MarketError::WrongNftResource => "wrong nft resource",
+      MarketError::WrongBadgeResource => "wrong badge resource",
+      MarketError::WrongReceiptResource => "wrong receipt resource",
+      MarketError::WrongCurrency => "wrong currency resource",
+      MarketError::SingleItemExpected => "the bucket should contain exactly one item",
+      MarketError::NegativeCost => "the cost should be positive",
+      MarketError::InvalidBadge => "invalid badge",
+      MarketError::NotListed => "nft not listed",
+      MarketError::ListingClosed => "listing already cancelled or bought",
+      MarketError::NothingToCollect => "nothing to collect",
+      MarketError::InsufficientPayment => "insufficient payment",
+      MarketError::SwapNotListed => "swap not listed",
+      MarketError::ItemNotWanted => "item not wanted by the seller",
+      MarketError::NegativeSweetener => "the sweetener should be positive",
+      MarketError::BundleNotListed => "bundle not listed",
+      MarketError::EmptyBundle => "empty bundle",
+      MarketError::RentalNotListed => "rental not listed",
+      MarketError::NotARental => "not a rental",
+      MarketError::AlreadyRented => "already rented",
+      MarketError::RentalOngoing => "rental not expired",
+      MarketError::InvalidDuration => "invalid duration",
+      MarketError::InvalidAmount => "the amount should be positive",
+      MarketError::NegativeInterest => "the interest should be positive",
+      MarketError::LoanOfferNotFound => "loan offer not found",
+      MarketError::LoanAlreadyTaken => "loan already taken",
+      MarketError::LoanNotTaken => "loan not taken",
+      MarketError::LoanDefaulted => "loan defaulted",
+      MarketError::LoanNotDefaulted => "loan not defaulted",
+      MarketError::AuctionNotListed => "auction not listed",
+      MarketError::InvalidSchedule => "invalid auction schedule",
+      MarketError::BidPhaseOver => "bid phase over",
+      MarketError::NotInRevealPhase => "not in reveal phase",
+      MarketError::CommitmentMismatch => "reveal does not match commitment",
+      MarketError::DepositTooLow => "deposit lower than the bid",
+      MarketError::RevealNotOver => "reveal phase not over",
+      MarketError::AlreadySettled => "already settled",
+      MarketError::InvalidReceipt => "invalid receipt",
+      MarketError::NothingToClaim => "nothing to claim yet",
+      MarketError::AuctionHasBids => "auction has bids",
+      MarketError::AuctionEnded => "auction ended",
+      MarketError::AuctionNotEnded => "auction not ended",
+      MarketError::BidTooLow => "bid too low",
+      MarketError::NoBidToSettle => "no bid to settle",
+      MarketError::NoBuyNowPrice => "no buy now price",
+      MarketError::InvalidBuyNowPrice => "buy now price below start price",
+      MarketError::InvalidExtension => "the extension should be positive",
+      MarketError::PoolNotFound => "pool not found",
+      MarketError::InvalidSpotPrice => "the spot price should be positive",
+      MarketError::NegativeSpread => "the spread should be positive",
+      MarketError::InvalidCurve => "invalid curve",
+      MarketError::NftNotInPool => "nft not in pool",
+      MarketError::PoolOutOfCurrency => "pool out of currency",
+      MarketError::PoolPriceExhausted => "pool price exhausted",
+      MarketError::InvalidWindow => "the window should be positive",
+      MarketError::BidNotFound => "bid not found",
+      MarketError::UnknownTraitField => "unknown trait field",
+      MarketError::TraitMismatch => "nft does not match the bid traits",
+      MarketError::ListingSold => "listing sold, collect the proceeds",
+      MarketError::ListingCancelled => "listing cancelled",
+      MarketError::ListingExpired => "listing expired, cancel to get the nft back",
+      MarketError::FixedPriceOnly => "only fixed price listings can be updated",
+      MarketError::CannotRelist => "bundles and rentals cannot be relisted",
+      MarketError::NotLiveYet => "listing not live yet",
+      MarketError::FractionNotFound => "fractionalized nft not found",
+      MarketError::BoughtOut => "shares already bought out",
+      MarketError::PartialShares => "redeeming the nft requires all the shares",
+      MarketError::LayawayNotOffered => "no layaway offered on this listing",
+      MarketError::LayawayOngoing => "listing locked by a layaway",
+      MarketError::LayawayNotFound => "layaway not found",
+      MarketError::LayawayDefaulted => "installment deadline passed",
+      MarketError::LayawayNotDefaulted => "installment deadline not passed",
+      MarketError::InvalidRate => "the rate should be between 0 and 1",
+      MarketError::RaffleNotListed => "raffle not listed",
+      MarketError::RaffleEnded => "raffle ended",
+      MarketError::RaffleSoldOut => "not enough tickets left",
+      MarketError::RaffleNotOver => "raffle neither sold out nor ended",
+      MarketError::RaffleHasTickets => "raffle has tickets",
+      MarketError::NegativeRewardRate => "the reward rates should be positive",
+      MarketError::StakingDisabled => "staking not enabled",
+      MarketError::WrongStakeToken => "wrong stake token",
+      MarketError::EscrowNotFound => "no payment held in escrow",
+      MarketError::EscrowOngoing => "listing locked by an escrowed payment",
+      MarketError::EscrowDisputed => "escrow disputed, waiting for the arbiter",
+      MarketError::EscrowNotDisputed => "escrow not disputed",
+      MarketError::EscrowNotOver => "confirmation delay not passed",
+      MarketError::NotEscrowParty => "neither the seller nor the buyer of the escrow",
+      MarketError::Migrated => "market migrated to its successor",
+      MarketError::WindingDown => "market winding down",
+      MarketError::NotWindingDown => "market not winding down",
+      MarketError::Flagged => "nft flagged by the moderator",
+      MarketError::NotFlagged => "nft not flagged",
+      MarketError::BuyNowOutbid => "a bid reached the buy now price",
+      MarketError::MarketCurrencyOnly => "only listings in the market currency can do this",
+      MarketError::EscrowedListing => "an escrowed listing cannot be offered in layaway"
+    }
+  }
+}
+
+impl fmt::Display for MarketError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "[E{:03}] {}", self.code(), self.message())
+  }
+}
+
+pub fn ensure(condition: bool, error: MarketError) {
+  if !condition {
+    panic!("{}", error);
+  }
+}
+
+pub trait OrPanic<T> {
+  fn or_panic(self, error: MarketError) -> T;
+}
+
+impl<T> OrPanic<T> for Option<T> {
+  fn or_panic(self, error: MarketError) -> T {
+    match self {
+      Some(value) => value,
+      None => panic!("{}", error)
+    }
+  }
+}
@@ -0,0 +1,34 @@
+use scrypto::prelude::*;
+
+// deterministic randomness source, to draw a known raffle winner in tests: its owner picks the winners,
+// a real raffle uses an oracle instead
+#[blueprint]
+mod fixed_random {
+  enable_method_auth! {
+    methods {
+      random => PUBLIC;
+      set_value => restrict_to: [OWNER];
+    }
+  }
+
+  struct FixedRandom {
+    value: u64
+  }
+
+  impl FixedRandom {
+    pub fn instantiate(value: u64, owner_rule: AccessRule) -> Global<FixedRandom> {
+        Self { value: value }
+            .instantiate()
+            .prepare_to_globalize(OwnerRole::Fixed(owner_rule))
+            .globalize()
+    }
+
+    pub fn random(&self, _seed: u64) -> u64 {
+        self.value
+    }
+
+    pub fn set_value(&mut self, value: u64) {
+        self.value = value;
+    }
+  }
+}
@@ -1,155 +1,2421 @@
-use scrypto::prelude::*;
-
-#[derive(NonFungibleData, ScryptoSbor)]
-pub struct Badge {
-  name: String,
-  description: String,
-  nft_address: ResourceAddress,
-  nft_id: NonFungibleLocalId,
-  component_address: ComponentAddress
-}
-
-#[blueprint]
-mod nft_secondary_market {
-  enable_method_auth! {
-    roles {
-      fee_owner => updatable_by: [];
-    },
-    methods {
-      sell => PUBLIC;
-      update => PUBLIC;
-      cancel => PUBLIC;
-      collect => PUBLIC;
-      buy => PUBLIC;
-      collect_fees => restrict_to: [fee_owner];
-    }
-  }
-    
-  struct NftSecondaryMarket {
-    nft_vault: NonFungibleVault,
-    ccy_vault: FungibleVault,
-    resource_manager: ResourceManager,
-    nft_address: ResourceAddress,
-    ccy_address: ResourceAddress,
-    badge_address: ResourceAddress,
-    badges: HashMap<NonFungibleLocalId, NonFungibleLocalId>, // badge id to nft id
-    offers: HashMap<NonFungibleLocalId, (NonFungibleLocalId, Decimal)>, // nft id to badge and cost
-    to_collect: HashMap<NonFungibleLocalId, Decimal>, // badge id to collect amount
-    component_address: ComponentAddress,
-    fee_badge: ResourceAddress,
-    fee_rate: Decimal,
-    fee_vault: FungibleVault,
-    fee_amount: Decimal
-  }
-
-  impl NftSecondaryMarket {
-    pub fn instantiate_component(nft_address: ResourceAddress, ccy_address: ResourceAddress, fee_badge: ResourceAddress, fee_rate: Decimal) -> Global<NftSecondaryMarket> {
-        let (address_reservation, component_address) = Runtime::allocate_component_address(NftSecondaryMarket::blueprint_id());
-        let resource_manager = ResourceBuilder::new_ruid_non_fungible::<Badge>(OwnerRole::None)
-                .metadata(metadata! { 
-                    init { 
-                        "name" => "Impahla secondary market badges", updatable; 
-                        "description" => "Seller badge for secondary market", updatable; 
-                        "component" => component_address, locked;
-                        "tags" => vec!["utility"], updatable;
-                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
-                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
-                    }
-                })
-                .mint_roles(mint_roles! (
-                    minter => rule!(require(global_caller(component_address))); 
-                    minter_updater => rule!(deny_all);
-                ))
-                .burn_roles(burn_roles! {
-                    burner => rule!(require(global_caller(component_address))); 
-                    burner_updater => rule!(deny_all);
-                })
-                .create_with_no_initial_supply();
-        let component = Self {
-                nft_vault: NonFungibleVault::new(nft_address),
-                ccy_vault: FungibleVault::new(ccy_address),
-                resource_manager: resource_manager,
-                nft_address: nft_address,
-                ccy_address: ccy_address,
-                badge_address: resource_manager.address(),
-                badges: HashMap::new(),
-                offers: HashMap::new(),
-                to_collect: HashMap::new(),
-                component_address: component_address,
-                fee_badge: fee_badge,
-                fee_rate: fee_rate,
-                fee_vault: FungibleVault::new(ccy_address),
-                fee_amount: dec!(0),
-            }.instantiate();
-        component.prepare_to_globalize(OwnerRole::None)
-                 .roles(roles! {
-                   fee_owner => rule!(require(fee_badge));
-                 })
-                 .with_address(address_reservation)
-                 .globalize()
-    }
-    
-    pub fn sell(&mut self, nft_bucket: NonFungibleBucket, cost: Decimal) -> NonFungibleBucket {
-        assert!(cost >= Decimal::zero(), "the cost should be positive");
-        assert!(nft_bucket.resource_address() == self.nft_address, "wrong nft ressource");
-        let nft_id = nft_bucket.non_fungible_local_id();
-        let badge_bucket = self.resource_manager.mint_ruid_non_fungible(Badge {
-            name: String::from("impahla seller badge"),
-            description: String::from("this badge allow you to interact with your offer in the secondary market"),
-            nft_address: self.nft_address,
-            nft_id: nft_id.clone(),
-            component_address: self.component_address
-          }).as_non_fungible();
-        let badge_id = badge_bucket.non_fungible_local_id();
-        self.badges.insert(badge_id.clone(), nft_id.clone());
-        self.offers.insert(nft_id, (badge_id, cost));
-        self.nft_vault.put(nft_bucket);
-        badge_bucket
-    }
-    
-    pub fn update(&mut self, badge_bucket: NonFungibleBucket, cost: Decimal) -> NonFungibleBucket {
-        assert!(cost >= Decimal::zero(), "the cost should be positive");
-        assert!(badge_bucket.resource_address() == self.badge_address, "wrong badge ressource");
-        let badge_id = badge_bucket.non_fungible_local_id();
-        let nft_id = self.badges.get(&badge_id).expect("invalid badge");
-        self.offers.remove(&nft_id).expect("already cancelled or bought");
-        self.offers.insert(nft_id.clone(), (badge_id, cost));
-        badge_bucket
-    }
-    
-    pub fn cancel(&mut self, badge_bucket: NonFungibleBucket) -> NonFungibleBucket {
-        assert!(badge_bucket.resource_address() == self.badge_address, "wrong badge ressource");
-        let badge_id = badge_bucket.non_fungible_local_id();
-        let nft_id = self.badges.remove(&badge_id).expect("invalid badge");
-        self.offers.remove(&nft_id).expect("already cancelled or bought");
-        badge_bucket.burn();
-        self.nft_vault.take_non_fungible(&nft_id)
-    }
-    
-    pub fn collect(&mut self, badge_bucket: NonFungibleBucket) -> FungibleBucket {
-        assert!(badge_bucket.resource_address() == self.badge_address, "wrong badge ressource");
-        let badge_id = badge_bucket.non_fungible_local_id();
-        let _nft_id = self.badges.remove(&badge_id).expect("invalid badge");
-        let cost = self.to_collect.remove(&badge_id).expect("already collected");
-        badge_bucket.burn();
-        self.ccy_vault.take(cost)
-    }
-    
-    pub fn buy(&mut self, nft_id: NonFungibleLocalId, mut ccy_bucket: FungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
-        let (badge_id, cost) = self.offers.remove(&nft_id).expect("invalid badge");
-        
-        let mut bucket = ccy_bucket.take(cost);
-        self.fee_vault.put(bucket.take(cost*self.fee_rate));
-        self.fee_amount = self.fee_vault.amount();
-        self.to_collect.insert(badge_id, bucket.amount());
-        self.ccy_vault.put(bucket);
-        let nft_bucket = self.nft_vault.take_non_fungible(&nft_id);
-        (nft_bucket, ccy_bucket)
-    }
-    
-    pub fn collect_fees(&mut self) -> FungibleBucket {
-        self.fee_amount = dec!(0);
-        self.fee_vault.take_all()
-    }
-  }
-}
+use scrypto::prelude::*;
+
+mod error;
+pub use error::*;
+mod random;
+pub use random::*;
+mod fixed_random;
+
+// number of sales kept for the price oracle
+const SALES_CAPACITY: usize = 64;
+// number of sales kept in the history of each NFT
+const HISTORY_LENGTH: usize = 32;
+// layout of the component state, bumped when migrate must convert it
+const STATE_VERSION: u32 = 1;
+
+// one sale in the provenance of an NFT
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct SaleRecord {
+  price: Decimal,
+  fee: Decimal,
+  time: Instant,
+  seller_badge: NonFungibleLocalId,
+  buyer: Option<NonFungibleGlobalId> // bid receipt of the winner, unknown for a direct buy
+}
+
+#[derive(NonFungibleData, ScryptoSbor)]
+pub struct Badge {
+  name: String,
+  description: String,
+  nft_address: ResourceAddress,
+  nft_id: NonFungibleLocalId,
+  component_address: ComponentAddress,
+  #[mutable]
+  reserve_price: Option<Decimal>, // public reserve of an auction
+  #[mutable]
+  buy_now_price: Option<Decimal>,
+  #[mutable]
+  end_time: Option<Instant>, // end of an auction, extended by late bids
+  #[mutable]
+  starts_at: Option<Instant> // a fixed price listing cannot be bought before
+}
+
+// lifecycle of a listing, kept per badge
+#[derive(ScryptoSbor, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingState {
+  Active,
+  Sold,      // proceeds waiting to be collected with the badge
+  Cancelled, // NFT returned to the seller
+  Expired    // auction ended without a winner, the seller cancels to get the NFT back
+}
+
+// english auction terms, bids below the reserve return the NFT to the seller
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct AuctionTerms {
+  start_price: Decimal,
+  reserve_price: Decimal,
+  public_reserve: bool, // an unlisted reserve is not shown on the badge, it stays readable in the component state
+  buy_now_price: Option<Decimal>,
+  end_time: Instant,
+  extension_minutes: i64 // a bid in the last minutes pushes the end this far
+}
+
+// non transferable proof of an ongoing rental, given to the renter
+#[derive(NonFungibleData, ScryptoSbor)]
+pub struct RentalReceipt {
+  name: String,
+  description: String,
+  nft_address: ResourceAddress,
+  nft_id: NonFungibleLocalId,
+  nft_data: ScryptoValue, // copy of the rented NFT data
+  expires_at: Instant,
+  component_address: ComponentAddress
+}
+
+// lender badge of a loan offer, or borrower badge of an ongoing loan
+#[derive(NonFungibleData, ScryptoSbor)]
+pub struct LoanBadge {
+  name: String,
+  description: String,
+  nft_address: ResourceAddress,
+  amount: Decimal,
+  interest: Decimal,
+  duration_days: u64,
+  component_address: ComponentAddress
+}
+
+// receipt of an escrowed bid, redeemed for the refund or the won NFT
+#[derive(NonFungibleData, ScryptoSbor)]
+pub struct BidReceipt {
+  name: String,
+  description: String,
+  nft_address: ResourceAddress,
+  nft_id: Option<NonFungibleLocalId>, // None for a collection bid
+  component_address: ComponentAddress
+}
+
+// sealed-bid auction: commitments until bid_end, reveals until reveal_end
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct SealedAuction {
+  badge_id: NonFungibleLocalId,
+  min_price: Decimal,
+  bid_end: Instant,
+  reveal_end: Instant,
+  second_price: bool, // the winner pays the second highest valid reveal (Vickrey)
+  bids: Vec<NonFungibleLocalId>, // bid receipt ids
+  settled: bool
+}
+
+// receipt of governance tokens staked for a part of the buy fees
+#[derive(NonFungibleData, ScryptoSbor)]
+pub struct StakeReceipt {
+  name: String,
+  description: String,
+  token: ResourceAddress,
+  amount: Decimal,
+  component_address: ComponentAddress
+}
+
+// liquidity provider badge of a pool
+#[derive(NonFungibleData, ScryptoSbor)]
+pub struct PoolBadge {
+  name: String,
+  description: String,
+  nft_address: ResourceAddress,
+  component_address: ComponentAddress
+}
+
+// how the pool spot price moves after each trade
+#[derive(ScryptoSbor, Clone, Debug)]
+pub enum Curve {
+  Linear(Decimal),     // spot price delta
+  Exponential(Decimal) // spot price factor, above one
+}
+
+impl Curve {
+  fn up(&self, spot_price: Decimal) -> Decimal {
+    match self {
+      Curve::Linear(delta) => spot_price + *delta,
+      Curve::Exponential(factor) => spot_price * *factor
+    }
+  }
+  
+  fn down(&self, spot_price: Decimal) -> Decimal {
+    match self {
+      Curve::Linear(delta) => {
+        ensure(spot_price >= *delta, MarketError::PoolPriceExhausted);
+        spot_price - *delta
+      },
+      Curve::Exponential(factor) => spot_price / *factor
+    }
+  }
+}
+
+// buyers pay the spot price plus the spread, sellers receive the next lower spot price
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct Pool {
+  nft_ids: Vec<NonFungibleLocalId>,
+  ccy_amount: Decimal,
+  spot_price: Decimal,
+  curve: Curve,
+  spread: Decimal
+}
+
+// NFT escrowed against a fungible share token, until bought out or redeemed with all the shares
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct Fraction {
+  nft_id: NonFungibleLocalId,
+  supply: Decimal, // outstanding shares
+  reserve_price: Decimal, // buyout price of all the shares
+  proceeds: Option<Decimal> // buyout CCY left to redeem, None until bought out
+}
+
+// raffle of a listed NFT, drawn once sold out or at the deadline
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct Raffle {
+  badge_id: NonFungibleLocalId,
+  ticket_price: Decimal,
+  ticket_count: u64,
+  deadline: Instant,
+  tickets: Vec<NonFungibleLocalId> // bid receipt ids, one per ticket
+}
+
+// installment plan offered by the seller of a fixed price listing
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct LayawayTerms {
+  deposit: Decimal,      // first payment, locks the listing to the buyer
+  installments: u64,     // equal payments of the rest of the cost
+  interval_days: i64,    // delay allowed before each installment
+  forfeit_rate: Decimal  // part of the deposit kept by the seller on default
+}
+
+// ongoing installment plan, keyed by the bid receipt of the buyer
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct Layaway {
+  nft_id: NonFungibleLocalId,
+  badge_id: NonFungibleLocalId,
+  cost: Decimal,
+  deposit: Decimal,
+  paid: Decimal,
+  installment: Decimal,
+  installments_left: u64,
+  deadline: Instant // of the next installment
+}
+
+// payment of an escrowed sale, keyed by the bid receipt of the buyer
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct Escrow {
+  nft_id: NonFungibleLocalId,
+  badge_id: NonFungibleLocalId,
+  cost: Decimal,
+  fee_rate: Decimal,
+  buyer: Option<NonFungibleGlobalId>,
+  deadline: Instant, // released to the seller afterwards unless disputed
+  disputed: bool
+}
+
+// constraint of a trait bid on a field of the NFT data, named after the layout set by the fee owner
+#[derive(ScryptoSbor, Clone, Debug)]
+pub struct TraitConstraint {
+  field: String,
+  value: String // compared to the field rendered as text (strings, booleans, integers, decimals)
+}
+
+// non fungible data of any collection, inspected as a raw SBOR value
+#[derive(ScryptoSbor)]
+#[sbor(transparent)]
+struct RawNonFungibleData(ScryptoValue);
+
+impl NonFungibleData for RawNonFungibleData {
+  const MUTABLE_FIELDS: &'static [&'static str] = &[];
+}
+
+// item a swap listing accepts in exchange of the escrowed NFT
+#[derive(ScryptoSbor, Clone, Debug)]
+pub enum SwapWant {
+  Ids(Vec<NonFungibleGlobalId>), // any of these specific NFTs
+  Resource(ResourceAddress)      // any NFT of this collection
+}
+
+// new terms of a listing converted in place by relist
+#[derive(ScryptoSbor, Clone, Debug)]
+pub enum ListingTerms {
+  FixedPrice(Decimal),
+  FixedPriceIn(ResourceAddress, Decimal), // fixed price in another fungible currency
+  Auction(AuctionTerms),
+  Swap(SwapWant, Decimal) // wanted item and currency sweetener
+}
+
+#[derive(ScryptoSbor, ScryptoEvent)]
+pub struct ListingUpdatedEvent {
+  badge_id: NonFungibleLocalId,
+  nft_id: NonFungibleLocalId,
+  terms: ListingTerms
+}
+
+// fixed price or buy now sale, with the fee rate applied after discounts
+#[derive(ScryptoSbor, ScryptoEvent)]
+pub struct SoldEvent {
+  badge_id: NonFungibleLocalId,
+  nft_id: NonFungibleLocalId,
+  price: Decimal,
+  fee_rate: Decimal,
+  fee: Decimal,
+  buyer: Option<NonFungibleGlobalId>
+}
+
+// an NFT flagged or unflagged by the moderator
+#[derive(ScryptoSbor, ScryptoEvent)]
+pub struct FlaggedEvent {
+  item: NonFungibleGlobalId,
+  flagged: bool
+}
+
+#[blueprint]
+#[events(ListingUpdatedEvent, SoldEvent, FlaggedEvent)]
+mod nft_secondary_market {
+  enable_method_auth! {
+    roles {
+      fee_owner => updatable_by: [];
+      arbiter => updatable_by: [fee_owner];
+      moderator => updatable_by: [fee_owner];
+    },
+    methods {
+      sell => PUBLIC;
+      update => PUBLIC;
+      relist => PUBLIC;
+      cancel => PUBLIC;
+      collect => PUBLIC;
+      buy => PUBLIC;
+      offer_layaway => PUBLIC;
+      start_layaway => PUBLIC;
+      pay_installment => PUBLIC;
+      default_layaway => PUBLIC;
+      sell_escrowed => PUBLIC;
+      confirm_escrow => PUBLIC;
+      timeout_escrow => PUBLIC;
+      dispute_escrow => PUBLIC;
+      resolve_escrow => restrict_to: [arbiter];
+      flag => restrict_to: [moderator];
+      unflag => restrict_to: [moderator];
+      seize_flagged => restrict_to: [moderator];
+      is_flagged => PUBLIC;
+      sell_raffle => PUBLIC;
+      buy_tickets => PUBLIC;
+      draw_raffle => PUBLIC;
+      sell_auction => PUBLIC;
+      bid => PUBLIC;
+      settle_auction => PUBLIC;
+      sell_swap => PUBLIC;
+      fill_swap => PUBLIC;
+      sell_bundle => PUBLIC;
+      buy_bundle => PUBLIC;
+      list_rental => PUBLIC;
+      rent => PUBLIC;
+      reclaim_rental => PUBLIC;
+      offer_loan => PUBLIC;
+      cancel_loan_offer => PUBLIC;
+      borrow => PUBLIC;
+      repay => PUBLIC;
+      collect_loan => PUBLIC;
+      sell_sealed => PUBLIC;
+      sealed_bid => PUBLIC;
+      reveal_bid => PUBLIC;
+      settle_sealed => PUBLIC;
+      claim_bid => PUBLIC;
+      create_pool => PUBLIC;
+      buy_from_pool => PUBLIC;
+      sell_to_pool => PUBLIC;
+      pool_prices => PUBLIC;
+      close_pool => PUBLIC;
+      fractionalize => PUBLIC;
+      buyout => PUBLIC;
+      redeem_shares => PUBLIC;
+      place_trait_bid => PUBLIC;
+      fill_trait_bid => PUBLIC;
+      cancel_trait_bid => PUBLIC;
+      set_trait_fields => restrict_to: [fee_owner];
+      allow_bundle_collection => restrict_to: [fee_owner];
+      last_sale_price => PUBLIC;
+      floor_price => PUBLIC;
+      twap => PUBLIC;
+      sales_history => PUBLIC;
+      listing_state => PUBLIC;
+      badge_listing => PUBLIC;
+      take_rewards => PUBLIC;
+      offer => PUBLIC;
+      collect_fees => restrict_to: [fee_owner];
+      collect_currency_fees => restrict_to: [fee_owner];
+      set_buyer_rule => restrict_to: [fee_owner];
+      set_randomness => restrict_to: [fee_owner];
+      claim_rewards => PUBLIC;
+      claim_account_rewards => PUBLIC;
+      set_reward_rates => restrict_to: [fee_owner];
+      set_reward_cap => restrict_to: [fee_owner];
+      set_discount => restrict_to: [fee_owner];
+      remove_discount => restrict_to: [fee_owner];
+      set_staking => restrict_to: [fee_owner];
+      migrate => restrict_to: [fee_owner];
+      wind_down => restrict_to: [fee_owner];
+      rescue => restrict_to: [fee_owner];
+      version => PUBLIC;
+      previous => PUBLIC;
+      successor => PUBLIC;
+      stake => PUBLIC;
+      unstake => PUBLIC;
+      claim_stake_rewards => PUBLIC;
+    }
+  }
+    
+  struct NftSecondaryMarket {
+    nft_vault: NonFungibleVault,
+    ccy_vault: FungibleVault,
+    resource_manager: ResourceManager,
+    nft_address: ResourceAddress,
+    ccy_address: ResourceAddress,
+    badge_address: ResourceAddress,
+    badges: KeyValueStore<NonFungibleLocalId, (NonFungibleLocalId, ListingState)>, // badge id to nft id and listing state, a badge of the previous component is read there until its state changes
+    offers: HashMap<NonFungibleLocalId, (NonFungibleLocalId, Decimal, Option<Instant>)>, // nft id to badge, cost and start time
+    auctions: HashMap<NonFungibleLocalId, (NonFungibleLocalId, AuctionTerms, Option<(NonFungibleLocalId, Decimal)>)>, // nft id to badge, terms and highest bid (bid receipt, amount)
+    to_collect: HashMap<NonFungibleLocalId, Decimal>, // badge id to collect amount
+    layaway_terms: HashMap<NonFungibleLocalId, (LayawayTerms, Option<NonFungibleLocalId>)>, // nft id to installment plan and the bid receipt locking the listing
+    layaways: HashMap<NonFungibleLocalId, Layaway>, // bid receipt id to ongoing plan
+    escrow_terms: HashMap<NonFungibleLocalId, (i64, Option<NonFungibleLocalId>)>, // nft id to confirmation delay in days and the bid receipt of the held payment
+    escrows: HashMap<NonFungibleLocalId, Escrow>, // bid receipt id to held payment
+    raffles: HashMap<NonFungibleLocalId, Raffle>, // nft id to raffle
+    randomness: Option<ComponentAddress>, // component drawing the raffles, None for RuidSource
+    swaps: HashMap<NonFungibleLocalId, (NonFungibleLocalId, SwapWant, Decimal)>, // nft id to badge, wanted item and currency sweetener
+    extra_vaults: HashMap<ResourceAddress, NonFungibleVault>, // NFTs of other collections (bundles, swap proceeds)
+    listing_currencies: HashMap<NonFungibleLocalId, ResourceAddress>, // badge id to the currency of a listing relisted out of the market currency
+    currency_vaults: HashMap<ResourceAddress, (FungibleVault, FungibleVault)>, // proceeds and fees of the sales in other currencies
+    nft_to_collect: HashMap<NonFungibleLocalId, NonFungibleGlobalId>, // badge id to NFT received in a swap
+    bundles: HashMap<NonFungibleLocalId, (Vec<NonFungibleGlobalId>, Decimal)>, // badge id to bundled NFTs and cost
+    bundle_collections: HashSet<ResourceAddress>, // collections allowed in bundles besides nft_address
+    rentals: HashMap<NonFungibleLocalId, (NonFungibleLocalId, Decimal, u64, Option<Instant>)>, // nft id to badge, price per day, max days and end of the current rental
+    rental_manager: ResourceManager,
+    loan_offers: HashMap<NonFungibleLocalId, (Decimal, Decimal, u64, Option<(NonFungibleLocalId, NonFungibleLocalId, Instant)>)>, // lender badge id to amount, interest, duration in days and ongoing loan (borrower badge, nft id, deadline)
+    loans: HashMap<NonFungibleLocalId, NonFungibleLocalId>, // borrower badge id to lender badge id
+    loan_manager: ResourceManager,
+    sealed_auctions: HashMap<NonFungibleLocalId, SealedAuction>, // nft id to auction
+    sealed_bids: HashMap<NonFungibleLocalId, (NonFungibleLocalId, Hash, Decimal, Option<Decimal>)>, // bid receipt id to nft id, commitment, deposit and revealed price
+    bid_claims: HashMap<NonFungibleLocalId, (Decimal, Option<NonFungibleLocalId>)>, // bid receipt id to refund and won nft id
+    bid_manager: ResourceManager,
+    pools: HashMap<NonFungibleLocalId, Pool>, // pool badge id to pool
+    pool_manager: ResourceManager,
+    fractions: HashMap<ResourceAddress, Fraction>, // share resource to fraction
+    trait_bids: HashMap<NonFungibleLocalId, (Decimal, Vec<TraitConstraint>)>, // bid receipt id to price and constraints
+    trait_fields: Vec<String>, // field names of the NFT data, in declaration order
+    component_address: ComponentAddress,
+    fee_badge: ResourceAddress,
+    fee_rate: Decimal,
+    fee_vault: FungibleVault,
+    fee_amount: Decimal,
+    discounts: Vec<(ResourceAddress, Decimal, Decimal)>, // loyalty resource, minimum amount held and discounted fee rate
+    sales: Vec<(Instant, Decimal)>, // ring buffer of the last sales
+    sales_head: usize, // oldest sale once the ring buffer is full
+    history: KeyValueStore<NonFungibleLocalId, Vec<SaleRecord>>, // nft id to its last sales, oldest first
+    buyer_rule: Option<AccessRule>, // required proof for buyers, None for an open market
+    reward_manager: ResourceManager,
+    reward_rates: (Decimal, Decimal), // reward per unit of CCY volume for the buyer and the seller
+    reward_cap: Decimal, // maximum rewards ever accrued
+    rewards_accrued: Decimal,
+    rewards: KeyValueStore<NonFungibleGlobalId, Decimal>, // seller badge or buyer NFT to unclaimed rewards
+    account_rewards: KeyValueStore<ComponentAddress, Decimal>, // seller account registered at sell to unclaimed rewards
+    stake_vault: Option<FungibleVault>, // staked governance tokens, None until staking is set
+    stake_manager: ResourceManager,
+    staker_share: Decimal, // part of each buy fee shared with the stakers
+    reward_per_share: Decimal, // CCY accrued per staked token since the start
+    stakes: HashMap<NonFungibleLocalId, (Decimal, Decimal)>, // stake receipt id to staked amount and reward_per_share at the last claim
+    version: u32, // STATE_VERSION of the blueprint that created the component, informational only: nothing reads it yet
+    auth_vault: FungibleVault,
+    previous: Option<ComponentAddress>, // component migrated from, keeping the older sales history and unclaimed rewards
+    successor: Option<ComponentAddress>, // component migrated to, this one is emptied
+    refund_accounts: HashMap<NonFungibleLocalId, Global<Account>>, // badge id to the seller account registered at sell, until rescued
+    winding_down: bool, // no new listing nor buy, the registered listings are rescued
+    rescue_cursor: Option<NonFungibleLocalId>, // last badge id examined by rescue
+    refused_accounts: HashSet<ComponentAddress>, // accounts that refused a rescue deposit, not tried again
+    flagged: HashSet<NonFungibleGlobalId> // NFTs reported stolen, kept out of every trade, their listing is frozen
+  }
+
+  impl NftSecondaryMarket {
+    pub fn instantiate_component(nft_address: ResourceAddress, ccy_address: ResourceAddress, fee_badge: ResourceAddress, fee_rate: Decimal, buyer_rule: Option<AccessRule>) -> Global<NftSecondaryMarket> {
+        let (address_reservation, component_address) = Runtime::allocate_component_address(NftSecondaryMarket::blueprint_id());
+        // kept by the component, updates the roles of the market resources on migrate
+        let auth_bucket = ResourceBuilder::new_fungible(OwnerRole::None)
+                .divisibility(DIVISIBILITY_NONE)
+                .metadata(metadata! {
+                    init {
+                        "name" => "Impahla secondary market authority", locked;
+                    }
+                })
+                .mint_initial_supply(1);
+        let auth_address = auth_bucket.resource_address();
+        let resource_manager = ResourceBuilder::new_ruid_non_fungible::<Badge>(OwnerRole::None)
+                .metadata(metadata! { 
+                    init { 
+                        "name" => "Impahla secondary market badges", updatable; 
+                        "description" => "Seller badge for secondary market", updatable; 
+                        "component" => component_address, locked;
+                        "tags" => vec!["utility"], updatable;
+                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
+                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
+                    }
+                })
+                .mint_roles(mint_roles! (
+                    minter => rule!(require(global_caller(component_address))); 
+                    minter_updater => rule!(require(auth_address));
+                ))
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(component_address))); 
+                    burner_updater => rule!(require(auth_address));
+                })
+                .non_fungible_data_update_roles(non_fungible_data_update_roles! {
+                    non_fungible_data_updater => rule!(require(global_caller(component_address)));
+                    non_fungible_data_updater_updater => rule!(require(auth_address));
+                })
+                .create_with_no_initial_supply();
+        let rental_manager = ResourceBuilder::new_ruid_non_fungible::<RentalReceipt>(OwnerRole::None)
+                .metadata(metadata! {
+                    init {
+                        "name" => "Impahla rental receipts", updatable;
+                        "description" => "Renter receipt for secondary market", updatable;
+                        "component" => component_address, locked;
+                        "tags" => vec!["utility"], updatable;
+                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
+                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
+                    }
+                })
+                .mint_roles(mint_roles! (
+                    minter => rule!(require(global_caller(component_address)));
+                    minter_updater => rule!(require(auth_address));
+                ))
+                .withdraw_roles(withdraw_roles! {
+                    withdrawer => rule!(deny_all);
+                    withdrawer_updater => rule!(deny_all);
+                })
+                .create_with_no_initial_supply();
+        let loan_manager = ResourceBuilder::new_ruid_non_fungible::<LoanBadge>(OwnerRole::None)
+                .metadata(metadata! {
+                    init {
+                        "name" => "Impahla loan badges", updatable;
+                        "description" => "Lender and borrower badge for secondary market", updatable;
+                        "component" => component_address, locked;
+                        "tags" => vec!["utility"], updatable;
+                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
+                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
+                    }
+                })
+                .mint_roles(mint_roles! (
+                    minter => rule!(require(global_caller(component_address)));
+                    minter_updater => rule!(require(auth_address));
+                ))
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(component_address)));
+                    burner_updater => rule!(require(auth_address));
+                })
+                .create_with_no_initial_supply();
+        let bid_manager = ResourceBuilder::new_ruid_non_fungible::<BidReceipt>(OwnerRole::None)
+                .metadata(metadata! {
+                    init {
+                        "name" => "Impahla bid receipts", updatable;
+                        "description" => "Bidder receipt for secondary market", updatable;
+                        "component" => component_address, locked;
+                        "tags" => vec!["utility"], updatable;
+                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
+                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
+                    }
+                })
+                .mint_roles(mint_roles! (
+                    minter => rule!(require(global_caller(component_address)));
+                    minter_updater => rule!(require(auth_address));
+                ))
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(component_address)));
+                    burner_updater => rule!(require(auth_address));
+                })
+                .create_with_no_initial_supply();
+        let pool_manager = ResourceBuilder::new_ruid_non_fungible::<PoolBadge>(OwnerRole::None)
+                .metadata(metadata! {
+                    init {
+                        "name" => "Impahla pool badges", updatable;
+                        "description" => "Liquidity provider badge for secondary market", updatable;
+                        "component" => component_address, locked;
+                        "tags" => vec!["utility"], updatable;
+                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
+                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
+                    }
+                })
+                .mint_roles(mint_roles! (
+                    minter => rule!(require(global_caller(component_address)));
+                    minter_updater => rule!(require(auth_address));
+                ))
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(component_address)));
+                    burner_updater => rule!(require(auth_address));
+                })
+                .create_with_no_initial_supply();
+        let reward_manager = ResourceBuilder::new_fungible(OwnerRole::None)
+                .metadata(metadata! {
+                    init {
+                        "name" => "Impahla trading rewards", updatable;
+                        "description" => "Reward of the buyers and sellers of the secondary market", updatable;
+                        "component" => component_address, locked;
+                        "tags" => vec!["reward"], updatable;
+                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
+                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
+                    }
+                })
+                .mint_roles(mint_roles! (
+                    minter => rule!(require(global_caller(component_address)));
+                    minter_updater => rule!(require(auth_address));
+                ))
+                .create_with_no_initial_supply();
+        let stake_manager = ResourceBuilder::new_ruid_non_fungible::<StakeReceipt>(OwnerRole::None)
+                .metadata(metadata! {
+                    init {
+                        "name" => "Impahla stake receipts", updatable;
+                        "description" => "Staker receipt for secondary market", updatable;
+                        "component" => component_address, locked;
+                        "tags" => vec!["utility"], updatable;
+                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
+                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
+                    }
+                })
+                .mint_roles(mint_roles! (
+                    minter => rule!(require(global_caller(component_address)));
+                    minter_updater => rule!(require(auth_address));
+                ))
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(component_address)));
+                    burner_updater => rule!(require(auth_address));
+                })
+                .create_with_no_initial_supply();
+        let component = Self {
+                nft_vault: NonFungibleVault::new(nft_address),
+                ccy_vault: FungibleVault::new(ccy_address),
+                resource_manager: resource_manager,
+                nft_address: nft_address,
+                ccy_address: ccy_address,
+                badge_address: resource_manager.address(),
+                badges: KeyValueStore::new(),
+                offers: HashMap::new(),
+                auctions: HashMap::new(),
+                to_collect: HashMap::new(),
+                layaway_terms: HashMap::new(),
+                layaways: HashMap::new(),
+                escrow_terms: HashMap::new(),
+                escrows: HashMap::new(),
+                raffles: HashMap::new(),
+                randomness: None,
+                swaps: HashMap::new(),
+                extra_vaults: HashMap::new(),
+                listing_currencies: HashMap::new(),
+                currency_vaults: HashMap::new(),
+                nft_to_collect: HashMap::new(),
+                bundles: HashMap::new(),
+                bundle_collections: HashSet::new(),
+                rentals: HashMap::new(),
+                rental_manager: rental_manager,
+                loan_offers: HashMap::new(),
+                loans: HashMap::new(),
+                loan_manager: loan_manager,
+                sealed_auctions: HashMap::new(),
+                sealed_bids: HashMap::new(),
+                bid_claims: HashMap::new(),
+                bid_manager: bid_manager,
+                pools: HashMap::new(),
+                pool_manager: pool_manager,
+                fractions: HashMap::new(),
+                trait_bids: HashMap::new(),
+                trait_fields: Vec::new(),
+                component_address: component_address,
+                fee_badge: fee_badge,
+                fee_rate: fee_rate,
+                fee_vault: FungibleVault::new(ccy_address),
+                fee_amount: dec!(0),
+                discounts: Vec::new(),
+                sales: Vec::new(),
+                sales_head: 0,
+                history: KeyValueStore::new(),
+                buyer_rule: buyer_rule,
+                reward_manager: reward_manager,
+                reward_rates: (dec!(0), dec!(0)),
+                reward_cap: dec!(0),
+                rewards_accrued: dec!(0),
+                rewards: KeyValueStore::new(),
+                account_rewards: KeyValueStore::new(),
+                stake_vault: None,
+                stake_manager: stake_manager,
+                staker_share: dec!(0),
+                reward_per_share: dec!(0),
+                stakes: HashMap::new(),
+                version: STATE_VERSION,
+                auth_vault: FungibleVault::with_bucket(auth_bucket),
+                previous: None,
+                successor: None,
+                refund_accounts: HashMap::new(),
+                winding_down: false,
+                rescue_cursor: None,
+                refused_accounts: HashSet::new(),
+                flagged: HashSet::new(),
+            }.instantiate();
+        component.prepare_to_globalize(OwnerRole::None)
+                 .roles(roles! {
+                   fee_owner => rule!(require(fee_badge));
+                   arbiter => rule!(require(fee_badge));
+                   moderator => rule!(require(fee_badge));
+                 })
+                 .with_address(address_reservation)
+                 .globalize()
+    }
+    
+    // the listing can be updated or cancelled before it goes live at starts_at,
+    // the NFT or the proceeds are pushed to the refund account if the market winds down
+    pub fn sell(&mut self, nft_bucket: NonFungibleBucket, cost: Decimal, starts_at: Option<Instant>, refund_account: Option<Global<Account>>) -> NonFungibleBucket {
+        ensure(cost >= Decimal::zero(), MarketError::NegativeCost);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        let badge_bucket = self.mint_badge(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()), None, starts_at);
+        let badge_id = badge_bucket.non_fungible_local_id();
+        self.badges.insert(badge_id.clone(), (nft_id.clone(), ListingState::Active));
+        if let Some(account) = refund_account {
+            self.refund_accounts.insert(badge_id.clone(), account);
+        }
+        self.offers.insert(nft_id, (badge_id, cost, starts_at));
+        self.nft_vault.put(nft_bucket);
+        badge_bucket
+    }
+    
+    pub fn update(&mut self, badge_bucket: NonFungibleBucket, cost: Decimal) -> NonFungibleBucket {
+        ensure(cost >= Decimal::zero(), MarketError::NegativeCost);
+        let badge_id = Self::single_id(&badge_bucket, self.badge_address, MarketError::WrongBadgeResource);
+        let (nft_id, state) = self.listing(&badge_id);
+        Self::ensure_active(state);
+        self.ensure_listed_by(&badge_id, &nft_id);
+        self.ensure_unlocked(&nft_id);
+        self.offers.get_mut(&nft_id).or_panic(MarketError::FixedPriceOnly).1 = cost;
+        let terms = match self.listing_currencies.get(&badge_id) {
+            Some(currency) => ListingTerms::FixedPriceIn(*currency, cost),
+            None => ListingTerms::FixedPrice(cost)
+        };
+        Runtime::emit_event(ListingUpdatedEvent { badge_id: badge_id, nft_id: nft_id, terms: terms });
+        badge_bucket
+    }
+    
+    // converts an active or expired listing to new terms, the NFT stays escrowed and the badge is kept
+    pub fn relist(&mut self, badge_bucket: NonFungibleBucket, terms: ListingTerms) -> NonFungibleBucket {
+        let badge_id = Self::single_id(&badge_bucket, self.badge_address, MarketError::WrongBadgeResource);
+        let (nft_id, state) = self.listing(&badge_id);
+        if state != ListingState::Expired {
+            Self::ensure_active(state);
+        }
+        ensure(!self.bundles.contains_key(&badge_id) && !self.rentals.contains_key(&nft_id), MarketError::CannotRelist);
+        self.ensure_listed_by(&badge_id, &nft_id);
+        self.remove_listing(&nft_id);
+        self.listing_currencies.remove(&badge_id);
+        match &terms {
+            ListingTerms::FixedPrice(cost) => {
+                ensure(*cost >= Decimal::zero(), MarketError::NegativeCost);
+                self.offers.insert(nft_id.clone(), (badge_id.clone(), *cost, None));
+            },
+            ListingTerms::FixedPriceIn(currency, cost) => {
+                ensure(*cost >= Decimal::zero(), MarketError::NegativeCost);
+                // the forfeited layaway deposits are in the market currency
+                ensure(!self.to_collect.contains_key(&badge_id), MarketError::CannotRelist);
+                if *currency != self.ccy_address {
+                    self.currency_vaults.entry(*currency).or_insert_with(|| (FungibleVault::new(*currency), FungibleVault::new(*currency)));
+                    self.listing_currencies.insert(badge_id.clone(), *currency);
+                }
+                self.offers.insert(nft_id.clone(), (badge_id.clone(), *cost, None));
+            },
+            ListingTerms::Auction(auction_terms) => {
+                Self::check_auction_terms(auction_terms);
+                self.auctions.insert(nft_id.clone(), (badge_id.clone(), auction_terms.clone(), None));
+            },
+            ListingTerms::Swap(wanted, sweetener) => {
+                ensure(*sweetener >= Decimal::zero(), MarketError::NegativeSweetener);
+                self.swaps.insert(nft_id.clone(), (badge_id.clone(), wanted.clone(), *sweetener));
+            }
+        }
+        let auction_terms = match &terms {
+            ListingTerms::Auction(auction_terms) => Some(auction_terms),
+            _ => None
+        };
+        self.resource_manager.update_non_fungible_data(&badge_id, "reserve_price", auction_terms.filter(|terms| terms.public_reserve).map(|terms| terms.reserve_price));
+        self.resource_manager.update_non_fungible_data(&badge_id, "buy_now_price", auction_terms.and_then(|terms| terms.buy_now_price));
+        self.resource_manager.update_non_fungible_data(&badge_id, "end_time", auction_terms.map(|terms| terms.end_time));
+        self.resource_manager.update_non_fungible_data(&badge_id, "starts_at", None::<Instant>);
+        self.set_state(&badge_id, ListingState::Active);
+        Runtime::emit_event(ListingUpdatedEvent { badge_id: badge_id, nft_id: nft_id, terms: terms });
+        badge_bucket
+    }
+    
+    // a sold listing is collected instead, with the seller rewards, otherwise the CCY bucket holds the forfeited layaway deposits
+    pub fn cancel(&mut self, badge_bucket: NonFungibleBucket) -> (FungibleBucket, Vec<NonFungibleBucket>, Option<FungibleBucket>) {
+        let badge_id = Self::single_id(&badge_bucket, self.badge_address, MarketError::WrongBadgeResource);
+        let (nft_id, state) = self.listing(&badge_id);
+        if state == ListingState::Sold {
+            let (ccy_bucket, nft_bucket, reward_bucket) = self.collect(badge_bucket);
+            return (ccy_bucket, nft_bucket.into_iter().collect(), reward_bucket);
+        }
+        ensure(state != ListingState::Cancelled, MarketError::ListingCancelled);
+        match self.bundles.get(&badge_id) {
+            Some((items, _)) => items.iter().for_each(|item| self.ensure_not_flagged(item)),
+            None => self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()))
+        }
+        badge_bucket.burn();
+        if let Some((items, _cost)) = self.bundles.remove(&badge_id) {
+            self.set_state(&badge_id, ListingState::Cancelled);
+            return (FungibleBucket::new(self.ccy_address), items.iter().map(|item| self.take_item(item)).collect(), None);
+        }
+        self.ensure_listed_by(&badge_id, &nft_id);
+        if self.rentals.contains_key(&nft_id) {
+            let (nft_bucket, ccy_bucket) = self.close_rental(&badge_id, &nft_id);
+            return (ccy_bucket, vec![nft_bucket], None);
+        }
+        self.set_state(&badge_id, ListingState::Cancelled);
+        // forfeited layaway deposits
+        let ccy_bucket = self.ccy_vault.take(self.to_collect.remove(&badge_id).unwrap_or(dec!(0)));
+        self.remove_listing(&nft_id);
+        (ccy_bucket, vec![self.nft_vault.take_non_fungible(&nft_id)], None)
+    }
+    
+    // the unclaimed rewards of the burnt badge are minted with the proceeds
+    pub fn collect(&mut self, badge_bucket: NonFungibleBucket) -> (FungibleBucket, Option<NonFungibleBucket>, Option<FungibleBucket>) {
+        let badge_id = Self::single_id(&badge_bucket, self.badge_address, MarketError::WrongBadgeResource);
+        let (_, state) = self.listing(&badge_id);
+        // the income of a seized listing is left to collect
+        ensure(state == ListingState::Sold || (state == ListingState::Cancelled && self.to_collect.contains_key(&badge_id)), MarketError::NothingToCollect);
+        badge_bucket.burn();
+        let (ccy_bucket, nft_bucket) = self.take_proceeds(&badge_id);
+        let reward = self.take_rewards_of(&NonFungibleGlobalId::new(self.badge_address, badge_id));
+        let reward_bucket = (reward > Decimal::zero()).then(|| self.reward_manager.mint(reward).as_fungible());
+        (ccy_bucket, nft_bucket, reward_bucket)
+    }
+    
+    // the optional buyer proof of any NFT identifies the buyer for the rewards and the sales history,
+    // the optional discount proof of a loyalty resource lowers the fee rate,
+    // an escrowed sale returns a bid receipt instead of the NFT
+    pub fn buy(&mut self, nft_id: NonFungibleLocalId, mut ccy_bucket: FungibleBucket, buyer_proof: Option<NonFungibleProof>, discount_proof: Option<Proof>) -> (NonFungibleBucket, FungibleBucket) {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let fee_rate = self.discounted_rate(discount_proof);
+        let buyer = buyer_proof.map(|proof| {
+            let proof = proof.skip_checking();
+            NonFungibleGlobalId::new(proof.resource_address(), proof.non_fungible_local_id())
+        });
+        let (badge_id, cost) = match self.offers.remove(&nft_id) {
+            Some((badge_id, cost, starts_at)) => {
+                Self::ensure_active(self.listing(&badge_id).1);
+                ensure(starts_at.map_or(true, |start| Self::is_past(start)), MarketError::NotLiveYet);
+                self.layaway_terms.remove(&nft_id);
+                (badge_id, cost)
+            },
+            None => self.take_buy_now(&nft_id)
+        };
+        if let Some(currency) = self.listing_currencies.get(&badge_id).cloned() {
+            return self.buy_in_currency(&nft_id, &badge_id, currency, cost, ccy_bucket, fee_rate);
+        }
+        let bucket = self.take_payment(&mut ccy_bucket, cost);
+        if let Some((confirm_days, _)) = self.escrow_terms.get(&nft_id).cloned() {
+            let receipt_bucket = self.mint_bid_receipt(Some(nft_id.clone()));
+            let receipt_id = receipt_bucket.non_fungible_local_id();
+            self.escrows.insert(receipt_id.clone(), Escrow {
+                nft_id: nft_id.clone(),
+                badge_id: badge_id,
+                cost: cost,
+                fee_rate: fee_rate,
+                buyer: buyer,
+                deadline: Clock::current_time_rounded_to_minutes().add_days(confirm_days).unwrap(),
+                disputed: false
+            });
+            self.escrow_terms.insert(nft_id, (confirm_days, Some(receipt_id)));
+            self.ccy_vault.put(bucket);
+            return (receipt_bucket, ccy_bucket);
+        }
+        self.accrue_sale_rewards(&badge_id, buyer.clone(), cost);
+        self.settle_sale(&nft_id, &badge_id, bucket, fee_rate, buyer);
+        let nft_bucket = self.nft_vault.take_non_fungible(&nft_id);
+        (nft_bucket, ccy_bucket)
+    }
+    
+    // fixed price listing whose payment is held until the buyer confirms or confirm_days pass
+    pub fn sell_escrowed(&mut self, nft_bucket: NonFungibleBucket, cost: Decimal, confirm_days: i64) -> NonFungibleBucket {
+        ensure(confirm_days > 0, MarketError::InvalidDuration);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        let badge_bucket = self.sell(nft_bucket, cost, None, None);
+        self.escrow_terms.insert(nft_id, (confirm_days, None));
+        badge_bucket
+    }
+    
+    // releases the payment to the seller, even during a dispute, and gives the NFT to the buyer
+    pub fn confirm_escrow(&mut self, receipt_bucket: NonFungibleBucket) -> (FungibleBucket, Option<NonFungibleBucket>) {
+        let receipt_id = Self::single_id(&receipt_bucket, self.bid_manager.address(), MarketError::WrongReceiptResource);
+        ensure(self.escrows.contains_key(&receipt_id), MarketError::EscrowNotFound);
+        self.release_escrow(&receipt_id);
+        self.claim_bid(receipt_bucket)
+    }
+    
+    // anyone releases an undisputed payment after the deadline, the buyer claims the NFT with the receipt
+    pub fn timeout_escrow(&mut self, nft_id: NonFungibleLocalId) {
+        let receipt_id = self.escrow_receipt(&nft_id);
+        let escrow = self.escrows.get(&receipt_id).unwrap();
+        ensure(!escrow.disputed, MarketError::EscrowDisputed);
+        ensure(Self::is_past(escrow.deadline), MarketError::EscrowNotOver);
+        self.release_escrow(&receipt_id);
+    }
+    
+    // the proof is the seller badge or the buyer receipt of the escrow
+    pub fn dispute_escrow(&mut self, nft_id: NonFungibleLocalId, proof: NonFungibleProof) {
+        let receipt_id = self.escrow_receipt(&nft_id);
+        let proof = proof.skip_checking();
+        let escrow = self.escrows.get_mut(&receipt_id).unwrap();
+        let party = match proof.resource_address() {
+            address if address == self.badge_address => escrow.badge_id.clone(),
+            address if address == self.bid_manager.address() => receipt_id,
+            _ => panic!("{}", MarketError::NotEscrowParty)
+        };
+        ensure(proof.non_fungible_local_ids().contains(&party), MarketError::NotEscrowParty);
+        escrow.disputed = true;
+    }
+    
+    // a refund leaves the listing expired until the seller cancels it or relists it (without escrow)
+    pub fn resolve_escrow(&mut self, nft_id: NonFungibleLocalId, release: bool) {
+        let receipt_id = self.escrow_receipt(&nft_id);
+        let escrow = self.escrows.get(&receipt_id).cloned().unwrap();
+        ensure(escrow.disputed, MarketError::EscrowNotDisputed);
+        if release {
+            self.release_escrow(&receipt_id);
+            return;
+        }
+        self.escrows.remove(&receipt_id);
+        self.bid_claims.insert(receipt_id, (escrow.cost, None));
+        self.offers.insert(nft_id.clone(), (escrow.badge_id.clone(), escrow.cost, None));
+        self.escrow_terms.get_mut(&nft_id).unwrap().1 = None;
+        self.set_state(&escrow.badge_id, ListingState::Expired);
+    }
+    
+    pub fn offer_layaway(&mut self, badge_bucket: NonFungibleBucket, terms: LayawayTerms) -> NonFungibleBucket {
+        let badge_id = Self::single_id(&badge_bucket, self.badge_address, MarketError::WrongBadgeResource);
+        let (nft_id, state) = self.listing(&badge_id);
+        Self::ensure_active(state);
+        self.ensure_listed_by(&badge_id, &nft_id);
+        self.ensure_unlocked(&nft_id);
+        ensure(self.offers.contains_key(&nft_id), MarketError::FixedPriceOnly);
+        ensure(!self.listing_currencies.contains_key(&badge_id), MarketError::MarketCurrencyOnly);
+        ensure(!self.escrow_terms.contains_key(&nft_id), MarketError::EscrowedListing);
+        ensure(terms.deposit > Decimal::zero(), MarketError::InvalidAmount);
+        ensure(terms.installments > 0 && terms.interval_days > 0, MarketError::InvalidDuration);
+        ensure(terms.forfeit_rate >= Decimal::zero() && terms.forfeit_rate <= Decimal::one(), MarketError::InvalidRate);
+        self.layaway_terms.insert(nft_id, (terms, None));
+        badge_bucket
+    }
+    
+    // the deposit locks the listing, the receipt claims the NFT once fully paid or the refund on default
+    pub fn start_layaway(&mut self, nft_id: NonFungibleLocalId, mut ccy_bucket: FungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        let (terms, _) = self.layaway_terms.get(&nft_id).cloned().or_panic(MarketError::LayawayNotOffered);
+        self.ensure_unlocked(&nft_id);
+        let (badge_id, cost, starts_at) = self.offers.remove(&nft_id).or_panic(MarketError::NotListed);
+        Self::ensure_active(self.listing(&badge_id).1);
+        ensure(starts_at.map_or(true, |start| Self::is_past(start)), MarketError::NotLiveYet);
+        ensure(terms.deposit <= cost, MarketError::InvalidAmount);
+        let deposit = self.take_payment(&mut ccy_bucket, terms.deposit);
+        self.ccy_vault.put(deposit);
+        let receipt_bucket = self.mint_bid_receipt(Some(nft_id.clone()));
+        let receipt_id = receipt_bucket.non_fungible_local_id();
+        self.layaways.insert(receipt_id.clone(), Layaway {
+            nft_id: nft_id.clone(),
+            badge_id: badge_id,
+            cost: cost,
+            deposit: terms.deposit,
+            paid: terms.deposit,
+            installment: (cost - terms.deposit) / Decimal::from(terms.installments),
+            installments_left: terms.installments,
+            deadline: Clock::current_time_rounded_to_minutes().add_days(terms.interval_days).unwrap()
+        });
+        self.layaway_terms.insert(nft_id, (terms, Some(receipt_id)));
+        (receipt_bucket, ccy_bucket)
+    }
+    
+    // anyone can pay for the receipt, the last installment completes the sale
+    pub fn pay_installment(&mut self, receipt_id: NonFungibleLocalId, mut ccy_bucket: FungibleBucket) -> FungibleBucket {
+        let mut layaway = self.layaways.remove(&receipt_id).or_panic(MarketError::LayawayNotFound);
+        ensure(!Self::is_past(layaway.deadline), MarketError::LayawayDefaulted);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, layaway.nft_id.clone()));
+        let amount = if layaway.installments_left == 1 { layaway.cost - layaway.paid } else { layaway.installment };
+        let payment = self.take_payment(&mut ccy_bucket, amount);
+        self.ccy_vault.put(payment);
+        layaway.paid += amount;
+        layaway.installments_left -= 1;
+        if layaway.installments_left > 0 {
+            let (terms, _) = self.layaway_terms.get(&layaway.nft_id).unwrap();
+            layaway.deadline = layaway.deadline.add_days(terms.interval_days).unwrap();
+            self.layaways.insert(receipt_id, layaway);
+            return ccy_bucket;
+        }
+        self.layaway_terms.remove(&layaway.nft_id);
+        let bucket = self.ccy_vault.take(layaway.cost);
+        self.settle_sale(&layaway.nft_id, &layaway.badge_id, bucket, self.fee_rate, Some(NonFungibleGlobalId::new(self.bid_manager.address(), receipt_id.clone())));
+        self.bid_claims.insert(receipt_id, (dec!(0), Some(layaway.nft_id)));
+        ccy_bucket
+    }
+    
+    // once an installment is late, anyone can release the listing: the seller keeps part of the deposit, the buyer claims the rest
+    pub fn default_layaway(&mut self, receipt_id: NonFungibleLocalId) {
+        let layaway = self.layaways.get(&receipt_id).cloned().or_panic(MarketError::LayawayNotFound);
+        ensure(Self::is_past(layaway.deadline), MarketError::LayawayNotDefaulted);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, layaway.nft_id.clone()));
+        self.layaways.remove(&receipt_id);
+        let (terms, _) = self.layaway_terms.get(&layaway.nft_id).cloned().unwrap();
+        let forfeited = layaway.deposit*terms.forfeit_rate;
+        let fee = forfeited*self.fee_rate;
+        self.fee_vault.put(self.ccy_vault.take(fee));
+        self.fee_amount = self.fee_vault.amount();
+        self.credit(&layaway.badge_id, forfeited - fee);
+        self.bid_claims.insert(receipt_id, (layaway.paid - forfeited, None));
+        self.offers.insert(layaway.nft_id.clone(), (layaway.badge_id, layaway.cost, None));
+        self.layaway_terms.insert(layaway.nft_id, (terms, None));
+    }
+    
+    pub fn sell_raffle(&mut self, nft_bucket: NonFungibleBucket, ticket_price: Decimal, ticket_count: u64, deadline: Instant) -> NonFungibleBucket {
+        ensure(ticket_price >= Decimal::zero(), MarketError::NegativeCost);
+        ensure(ticket_count > 0, MarketError::InvalidAmount);
+        ensure(!Self::is_past(deadline), MarketError::InvalidSchedule);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        let badge_bucket = self.mint_badge(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()), None, None);
+        let badge_id = badge_bucket.non_fungible_local_id();
+        self.badges.insert(badge_id.clone(), (nft_id.clone(), ListingState::Active));
+        self.raffles.insert(nft_id, Raffle {
+            badge_id: badge_id,
+            ticket_price: ticket_price,
+            ticket_count: ticket_count,
+            deadline: deadline,
+            tickets: Vec::new()
+        });
+        self.nft_vault.put(nft_bucket);
+        badge_bucket
+    }
+    
+    // each ticket is a bid receipt, the winning one claims the NFT with claim_bid
+    pub fn buy_tickets(&mut self, nft_id: NonFungibleLocalId, count: u64, mut ccy_bucket: FungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let raffle = self.raffles.get(&nft_id).cloned().or_panic(MarketError::RaffleNotListed);
+        ensure(!Self::is_past(raffle.deadline), MarketError::RaffleEnded);
+        ensure(count > 0 && raffle.tickets.len() as u64 + count <= raffle.ticket_count, MarketError::RaffleSoldOut);
+        let payment = self.take_payment(&mut ccy_bucket, raffle.ticket_price * Decimal::from(count));
+        self.ccy_vault.put(payment);
+        let mut ticket_bucket = NonFungibleBucket::new(self.bid_manager.address());
+        for _ in 0..count {
+            let receipt_bucket = self.mint_bid_receipt(Some(nft_id.clone()));
+            self.raffles.get_mut(&nft_id).unwrap().tickets.push(receipt_bucket.non_fungible_local_id());
+            ticket_bucket.put(receipt_bucket);
+        }
+        (ticket_bucket, ccy_bucket)
+    }
+    
+    // once sold out or past the deadline, anyone can draw with the randomness component, the fee owner only without it:
+    // without tickets the seller cancels to get the NFT back
+    pub fn draw_raffle(&mut self, nft_id: NonFungibleLocalId) {
+        let raffle = self.raffles.get(&nft_id).cloned().or_panic(MarketError::RaffleNotListed);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let sold_out = raffle.tickets.len() as u64 == raffle.ticket_count;
+        ensure(sold_out || Self::is_past(raffle.deadline), MarketError::RaffleNotOver);
+        if raffle.tickets.is_empty() {
+            self.set_state(&raffle.badge_id, ListingState::Expired);
+            return;
+        }
+        let source: Box<dyn RandomSource> = match self.randomness {
+            Some(address) => Box::new(Global::<AnyComponent>::from(address)),
+            None => {
+                // a draw derived from the transaction can be replayed until it wins, only the fee owner draws it
+                Runtime::assert_access_rule(rule!(require(self.fee_badge)));
+                Box::new(RuidSource)
+            }
+        };
+        let count = raffle.tickets.len() as u64;
+        let winner_id = raffle.tickets[(source.random(count) % count) as usize].clone();
+        self.raffles.remove(&nft_id);
+        let proceeds = raffle.ticket_price * Decimal::from(count);
+        let bucket = self.ccy_vault.take(proceeds);
+        self.settle_sale(&nft_id, &raffle.badge_id, bucket, self.fee_rate, Some(NonFungibleGlobalId::new(self.bid_manager.address(), winner_id.clone())));
+        self.bid_claims.insert(winner_id, (dec!(0), Some(nft_id)));
+    }
+    
+    pub fn sell_auction(&mut self, nft_bucket: NonFungibleBucket, terms: AuctionTerms) -> NonFungibleBucket {
+        Self::check_auction_terms(&terms);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        let badge_bucket = self.mint_badge(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()), Some(&terms), None);
+        let badge_id = badge_bucket.non_fungible_local_id();
+        self.badges.insert(badge_id.clone(), (nft_id.clone(), ListingState::Active));
+        self.auctions.insert(nft_id, (badge_id, terms, None));
+        self.nft_vault.put(nft_bucket);
+        badge_bucket
+    }
+    
+    // the outbid receipt can claim its refund right away
+    pub fn bid(&mut self, nft_id: NonFungibleLocalId, ccy_bucket: FungibleBucket) -> NonFungibleBucket {
+        self.check_buyer();
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let (badge_id, mut terms, highest) = self.auctions.get(&nft_id).cloned().or_panic(MarketError::AuctionNotListed);
+        ensure(!Self::is_past(terms.end_time), MarketError::AuctionEnded);
+        let amount = ccy_bucket.amount();
+        ensure(amount >= terms.start_price && highest.as_ref().map_or(true, |(_, best)| amount > *best), MarketError::BidTooLow);
+        if let Some((outbid_id, outbid)) = highest {
+            self.bid_claims.insert(outbid_id, (outbid, None));
+        }
+        let now = Clock::current_time_rounded_to_minutes();
+        let extended_end = now.add_minutes(terms.extension_minutes).unwrap();
+        if extended_end.seconds_since_unix_epoch > terms.end_time.seconds_since_unix_epoch {
+            terms.end_time = extended_end;
+            self.resource_manager.update_non_fungible_data(&badge_id, "end_time", Some(extended_end));
+        }
+        let receipt_bucket = self.mint_bid_receipt(Some(nft_id.clone()));
+        self.auctions.insert(nft_id, (badge_id, terms, Some((receipt_bucket.non_fungible_local_id(), amount))));
+        self.ccy_vault.put(ccy_bucket);
+        receipt_bucket
+    }
+    
+    // once ended, anyone can settle: below the reserve the seller cancels to get the NFT back
+    pub fn settle_auction(&mut self, nft_id: NonFungibleLocalId) {
+        let (badge_id, terms, highest) = self.auctions.get(&nft_id).cloned().or_panic(MarketError::AuctionNotListed);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        ensure(Self::is_past(terms.end_time), MarketError::AuctionNotEnded);
+        let (receipt_id, amount) = highest.or_panic(MarketError::NoBidToSettle);
+        if amount >= terms.reserve_price {
+            self.auctions.remove(&nft_id);
+            let bucket = self.ccy_vault.take(amount);
+            self.settle_sale(&nft_id, &badge_id, bucket, self.fee_rate, Some(NonFungibleGlobalId::new(self.bid_manager.address(), receipt_id.clone())));
+            self.bid_claims.insert(receipt_id, (dec!(0), Some(nft_id)));
+        } else {
+            self.bid_claims.insert(receipt_id, (amount, None));
+            self.set_state(&badge_id, ListingState::Expired);
+            self.auctions.insert(nft_id, (badge_id, terms, None));
+        }
+    }
+    
+    pub fn sell_swap(&mut self, nft_bucket: NonFungibleBucket, wanted: SwapWant, sweetener: Decimal) -> NonFungibleBucket {
+        ensure(sweetener >= Decimal::zero(), MarketError::NegativeSweetener);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        let badge_bucket = self.mint_badge(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()), None, None);
+        let badge_id = badge_bucket.non_fungible_local_id();
+        self.badges.insert(badge_id.clone(), (nft_id.clone(), ListingState::Active));
+        self.swaps.insert(nft_id, (badge_id, wanted, sweetener));
+        self.nft_vault.put(nft_bucket);
+        badge_bucket
+    }
+    
+    pub fn fill_swap(&mut self, nft_id: NonFungibleLocalId, item_bucket: NonFungibleBucket, mut ccy_bucket: FungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        let (badge_id, wanted, sweetener) = self.swaps.remove(&nft_id).or_panic(MarketError::SwapNotListed);
+        let item_id = NonFungibleGlobalId::new(item_bucket.resource_address(), Self::single_id(&item_bucket, item_bucket.resource_address(), MarketError::ItemNotWanted));
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        self.ensure_not_flagged(&item_id);
+        let accepted = match &wanted {
+            SwapWant::Ids(ids) => ids.contains(&item_id),
+            SwapWant::Resource(address) => *address == item_id.resource_address()
+        };
+        ensure(accepted, MarketError::ItemNotWanted);
+        
+        let mut bucket = self.take_payment(&mut ccy_bucket, sweetener);
+        self.fee_vault.put(bucket.take(sweetener*self.fee_rate));
+        self.fee_amount = self.fee_vault.amount();
+        self.set_state(&badge_id, ListingState::Sold);
+        self.credit(&badge_id, bucket.amount());
+        self.ccy_vault.put(bucket);
+        self.put_items(item_bucket);
+        self.nft_to_collect.insert(badge_id, item_id);
+        let nft_bucket = self.nft_vault.take_non_fungible(&nft_id);
+        (nft_bucket, ccy_bucket)
+    }
+    
+    pub fn sell_bundle(&mut self, nft_buckets: Vec<NonFungibleBucket>, cost: Decimal) -> NonFungibleBucket {
+        ensure(cost >= Decimal::zero(), MarketError::NegativeCost);
+        let mut items: Vec<NonFungibleGlobalId> = Vec::new();
+        for nft_bucket in nft_buckets {
+            let address = nft_bucket.resource_address();
+            ensure(address == self.nft_address || self.bundle_collections.contains(&address), MarketError::WrongNftResource);
+            items.extend(nft_bucket.non_fungible_local_ids().into_iter().map(|id| NonFungibleGlobalId::new(address, id)));
+            self.put_items(nft_bucket);
+        }
+        ensure(!items.is_empty(), MarketError::EmptyBundle);
+        items.iter().for_each(|item| self.ensure_not_flagged(item));
+        let badge_bucket = self.mint_badge(&items[0], None, None);
+        let badge_id = badge_bucket.non_fungible_local_id();
+        // only the state is read for a bundle badge, its items are never looked up by nft id
+        self.badges.insert(badge_id.clone(), (items[0].local_id().clone(), ListingState::Active));
+        self.bundles.insert(badge_id, (items, cost));
+        badge_bucket
+    }
+    
+    pub fn buy_bundle(&mut self, bundle_id: NonFungibleLocalId, mut ccy_bucket: FungibleBucket) -> (Vec<NonFungibleBucket>, FungibleBucket) {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        let (items, cost) = self.bundles.remove(&bundle_id).or_panic(MarketError::BundleNotListed);
+        items.iter().for_each(|item| self.ensure_not_flagged(item));
+        
+        let mut bucket = self.take_payment(&mut ccy_bucket, cost);
+        self.fee_vault.put(bucket.take(cost*self.fee_rate));
+        self.fee_amount = self.fee_vault.amount();
+        self.set_state(&bundle_id, ListingState::Sold);
+        self.credit(&bundle_id, bucket.amount());
+        self.ccy_vault.put(bucket);
+        let nft_buckets = items.iter().map(|item| self.take_item(item)).collect();
+        (nft_buckets, ccy_bucket)
+    }
+    
+    pub fn list_rental(&mut self, nft_bucket: NonFungibleBucket, price_per_day: Decimal, max_days: u64) -> NonFungibleBucket {
+        ensure(price_per_day >= Decimal::zero(), MarketError::NegativeCost);
+        ensure(max_days > 0, MarketError::InvalidDuration);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        let badge_bucket = self.mint_badge(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()), None, None);
+        let badge_id = badge_bucket.non_fungible_local_id();
+        self.badges.insert(badge_id.clone(), (nft_id.clone(), ListingState::Active));
+        self.rentals.insert(nft_id, (badge_id.clone(), price_per_day, max_days, None));
+        self.to_collect.insert(badge_id, dec!(0));
+        self.nft_vault.put(nft_bucket);
+        badge_bucket
+    }
+    
+    pub fn rent(&mut self, nft_id: NonFungibleLocalId, days: u64, mut ccy_bucket: FungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        self.check_buyer();
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let (badge_id, price_per_day, max_days, rented_until) = self.rentals.get(&nft_id).cloned().or_panic(MarketError::RentalNotListed);
+        ensure(days > 0 && days <= max_days, MarketError::InvalidDuration);
+        ensure(rented_until.map_or(true, |end| Self::is_past(end)), MarketError::AlreadyRented);
+        let expires_at = Clock::current_time_rounded_to_minutes().add_days(days as i64).unwrap();
+        self.rentals.insert(nft_id.clone(), (badge_id.clone(), price_per_day, max_days, Some(expires_at)));
+        
+        let cost = price_per_day * Decimal::from(days);
+        let mut bucket = self.take_payment(&mut ccy_bucket, cost);
+        self.fee_vault.put(bucket.take(cost*self.fee_rate));
+        self.fee_amount = self.fee_vault.amount();
+        *self.to_collect.get_mut(&badge_id).unwrap() += bucket.amount();
+        self.ccy_vault.put(bucket);
+        let data = ResourceManager::from(self.nft_address).get_non_fungible_data::<RawNonFungibleData>(&nft_id);
+        let receipt_bucket = self.rental_manager.mint_ruid_non_fungible(RentalReceipt {
+            name: String::from("impahla rental receipt"),
+            description: String::from("this receipt proves the rental of the NFT until its expiry"),
+            nft_address: self.nft_address,
+            nft_id: nft_id,
+            nft_data: data.0,
+            expires_at: expires_at,
+            component_address: self.component_address
+          }).as_non_fungible();
+        (receipt_bucket, ccy_bucket)
+    }
+    
+    pub fn reclaim_rental(&mut self, badge_bucket: NonFungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        let badge_id = Self::single_id(&badge_bucket, self.badge_address, MarketError::WrongBadgeResource);
+        let (nft_id, state) = self.listing(&badge_id);
+        Self::ensure_active(state);
+        ensure(!self.bundles.contains_key(&badge_id) && self.rentals.contains_key(&nft_id), MarketError::NotARental);
+        self.ensure_listed_by(&badge_id, &nft_id);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        badge_bucket.burn();
+        self.close_rental(&badge_id, &nft_id)
+    }
+    
+    pub fn offer_loan(&mut self, ccy_bucket: FungibleBucket, interest: Decimal, duration_days: u64) -> NonFungibleBucket {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        ensure(interest >= Decimal::zero(), MarketError::NegativeInterest);
+        ensure(duration_days > 0, MarketError::InvalidDuration);
+        let amount = ccy_bucket.amount();
+        ensure(amount > Decimal::zero(), MarketError::InvalidAmount);
+        let badge_bucket = self.mint_loan_badge("impahla lender badge", amount, interest, duration_days);
+        self.loan_offers.insert(badge_bucket.non_fungible_local_id(), (amount, interest, duration_days, None));
+        self.ccy_vault.put(ccy_bucket);
+        badge_bucket
+    }
+    
+    pub fn cancel_loan_offer(&mut self, badge_bucket: NonFungibleBucket) -> FungibleBucket {
+        let badge_id = Self::single_id(&badge_bucket, self.loan_manager.address(), MarketError::WrongBadgeResource);
+        let (amount, _, _, loan) = self.loan_offers.get(&badge_id).or_panic(MarketError::InvalidBadge).clone();
+        ensure(loan.is_none(), MarketError::LoanAlreadyTaken);
+        self.loan_offers.remove(&badge_id);
+        badge_bucket.burn();
+        self.ccy_vault.take(amount)
+    }
+    
+    pub fn borrow(&mut self, offer_id: NonFungibleLocalId, nft_bucket: NonFungibleBucket) -> (FungibleBucket, NonFungibleBucket) {
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let (amount, interest, duration_days, loan) = self.loan_offers.get(&offer_id).or_panic(MarketError::LoanOfferNotFound).clone();
+        ensure(loan.is_none(), MarketError::LoanAlreadyTaken);
+        let deadline = Clock::current_time_rounded_to_minutes().add_days(duration_days as i64).unwrap();
+        let badge_bucket = self.mint_loan_badge("impahla borrower badge", amount, interest, duration_days);
+        let badge_id = badge_bucket.non_fungible_local_id();
+        self.loan_offers.insert(offer_id.clone(), (amount, interest, duration_days, Some((badge_id.clone(), nft_id, deadline))));
+        self.loans.insert(badge_id, offer_id);
+        self.nft_vault.put(nft_bucket);
+        (self.ccy_vault.take(amount), badge_bucket)
+    }
+    
+    pub fn repay(&mut self, badge_bucket: NonFungibleBucket, mut ccy_bucket: FungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        let badge_id = Self::single_id(&badge_bucket, self.loan_manager.address(), MarketError::WrongBadgeResource);
+        let offer_id = self.loans.remove(&badge_id).or_panic(MarketError::InvalidBadge);
+        let (amount, interest, _, loan) = self.loan_offers.remove(&offer_id).unwrap();
+        let (_, nft_id, deadline) = loan.unwrap();
+        ensure(!Self::is_past(deadline), MarketError::LoanDefaulted);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        
+        let payment = self.take_payment(&mut ccy_bucket, amount + interest);
+        self.ccy_vault.put(payment);
+        self.to_collect.insert(offer_id, amount + interest);
+        badge_bucket.burn();
+        (self.nft_vault.take_non_fungible(&nft_id), ccy_bucket)
+    }
+    
+    // the lender gets the repayment, or the NFT once the loan defaulted
+    pub fn collect_loan(&mut self, badge_bucket: NonFungibleBucket) -> (FungibleBucket, Option<NonFungibleBucket>) {
+        let badge_id = Self::single_id(&badge_bucket, self.loan_manager.address(), MarketError::WrongBadgeResource);
+        if let Some(repaid) = self.to_collect.remove(&badge_id) {
+            badge_bucket.burn();
+            return (self.ccy_vault.take(repaid), None);
+        }
+        let (_, _, _, loan) = self.loan_offers.get(&badge_id).or_panic(MarketError::InvalidBadge).clone();
+        let (borrower_badge_id, nft_id, deadline) = loan.or_panic(MarketError::LoanNotTaken);
+        ensure(Self::is_past(deadline), MarketError::LoanNotDefaulted);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        self.loan_offers.remove(&badge_id);
+        self.loans.remove(&borrower_badge_id);
+        badge_bucket.burn();
+        (FungibleBucket::new(self.ccy_address), Some(self.nft_vault.take_non_fungible(&nft_id)))
+    }
+    
+    pub fn sell_sealed(&mut self, nft_bucket: NonFungibleBucket, min_price: Decimal, bid_end: Instant, reveal_end: Instant, second_price: bool) -> NonFungibleBucket {
+        ensure(min_price >= Decimal::zero(), MarketError::NegativeCost);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        ensure(!Self::is_past(bid_end) && bid_end.seconds_since_unix_epoch < reveal_end.seconds_since_unix_epoch, MarketError::InvalidSchedule);
+        let badge_bucket = self.mint_badge(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()), None, None);
+        let badge_id = badge_bucket.non_fungible_local_id();
+        self.badges.insert(badge_id.clone(), (nft_id.clone(), ListingState::Active));
+        self.sealed_auctions.insert(nft_id, SealedAuction {
+            badge_id: badge_id,
+            min_price: min_price,
+            bid_end: bid_end,
+            reveal_end: reveal_end,
+            second_price: second_price,
+            bids: Vec::new(),
+            settled: false
+        });
+        self.nft_vault.put(nft_bucket);
+        badge_bucket
+    }
+    
+    // commitment is the hash of the SBOR encoded (price, salt)
+    pub fn sealed_bid(&mut self, nft_id: NonFungibleLocalId, commitment: Hash, deposit: FungibleBucket) -> NonFungibleBucket {
+        self.check_buyer();
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let bid_end = self.sealed_auctions.get(&nft_id).or_panic(MarketError::AuctionNotListed).bid_end;
+        ensure(!Self::is_past(bid_end), MarketError::BidPhaseOver);
+        let receipt_bucket = self.mint_bid_receipt(Some(nft_id.clone()));
+        let receipt_id = receipt_bucket.non_fungible_local_id();
+        self.sealed_auctions.get_mut(&nft_id).unwrap().bids.push(receipt_id.clone());
+        self.sealed_bids.insert(receipt_id, (nft_id, commitment, deposit.amount(), None));
+        self.ccy_vault.put(deposit);
+        receipt_bucket
+    }
+    
+    pub fn reveal_bid(&mut self, receipt_bucket: NonFungibleBucket, price: Decimal, salt: String) -> NonFungibleBucket {
+        let receipt_id = Self::single_id(&receipt_bucket, self.bid_manager.address(), MarketError::WrongReceiptResource);
+        let (nft_id, commitment, deposit, _) = self.sealed_bids.get(&receipt_id).or_panic(MarketError::InvalidReceipt).clone();
+        let auction = self.sealed_auctions.get(&nft_id).or_panic(MarketError::AuctionNotListed);
+        ensure(Self::is_past(auction.bid_end) && !Self::is_past(auction.reveal_end), MarketError::NotInRevealPhase);
+        ensure(hash(scrypto_encode(&(price, salt)).unwrap()) == commitment, MarketError::CommitmentMismatch);
+        ensure(price <= deposit, MarketError::DepositTooLow);
+        self.sealed_bids.insert(receipt_id, (nft_id, commitment, deposit, Some(price)));
+        receipt_bucket
+    }
+    
+    // once the reveal phase is over, anyone can settle: losers are fully refunded
+    pub fn settle_sealed(&mut self, nft_id: NonFungibleLocalId) {
+        let mut auction = self.sealed_auctions.get(&nft_id).or_panic(MarketError::AuctionNotListed).clone();
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        ensure(Self::is_past(auction.reveal_end), MarketError::RevealNotOver);
+        ensure(!auction.settled, MarketError::AlreadySettled);
+        let mut prices: Vec<(Decimal, NonFungibleLocalId)> = auction.bids.iter()
+            .filter_map(|receipt_id| {
+                let (_, _, _, revealed) = self.sealed_bids.get(receipt_id).unwrap();
+                revealed.filter(|price| *price >= auction.min_price).map(|price| (price, receipt_id.clone()))
+            })
+            .collect();
+        // highest first, the earliest bid wins a tie
+        prices.sort_by(|a, b| b.0.cmp(&a.0));
+        let winner = prices.first().map(|(price, receipt_id)| {
+            let payment = if auction.second_price { prices.get(1).map_or(auction.min_price, |second| second.0) } else { *price };
+            (receipt_id.clone(), payment)
+        });
+        for receipt_id in auction.bids.iter() {
+            let (_, _, deposit, _) = self.sealed_bids.remove(receipt_id).unwrap();
+            match &winner {
+                Some((winner_id, payment)) if winner_id == receipt_id =>
+                    self.bid_claims.insert(receipt_id.clone(), (deposit - *payment, Some(nft_id.clone()))),
+                _ => self.bid_claims.insert(receipt_id.clone(), (deposit, None))
+            };
+        }
+        match winner {
+            Some((receipt_id, payment)) => {
+                self.sealed_auctions.remove(&nft_id);
+                let bucket = self.ccy_vault.take(payment);
+                self.settle_sale(&nft_id, &auction.badge_id, bucket, self.fee_rate, Some(NonFungibleGlobalId::new(self.bid_manager.address(), receipt_id)));
+            },
+            None => {
+                // no valid bid, the seller cancels to get the NFT back
+                self.set_state(&auction.badge_id, ListingState::Expired);
+                auction.settled = true;
+                self.sealed_auctions.insert(nft_id, auction);
+            }
+        }
+    }
+    
+    pub fn claim_bid(&mut self, receipt_bucket: NonFungibleBucket) -> (FungibleBucket, Option<NonFungibleBucket>) {
+        let receipt_id = Self::single_id(&receipt_bucket, self.bid_manager.address(), MarketError::WrongReceiptResource);
+        let (refund, nft_id) = self.bid_claims.remove(&receipt_id).or_panic(MarketError::NothingToClaim);
+        receipt_bucket.burn();
+        (self.ccy_vault.take(refund), nft_id.map(|nft_id| self.nft_vault.take_non_fungible(&nft_id)))
+    }
+    
+    pub fn create_pool(&mut self, nft_bucket: NonFungibleBucket, ccy_bucket: FungibleBucket, spot_price: Decimal, curve: Curve, spread: Decimal) -> NonFungibleBucket {
+        ensure(!self.winding_down, MarketError::WindingDown);
+        ensure(spot_price > Decimal::zero(), MarketError::InvalidSpotPrice);
+        ensure(spread >= Decimal::zero(), MarketError::NegativeSpread);
+        match &curve {
+            Curve::Linear(delta) => ensure(*delta >= Decimal::zero(), MarketError::InvalidCurve),
+            Curve::Exponential(factor) => ensure(*factor >= Decimal::one(), MarketError::InvalidCurve)
+        }
+        ensure(nft_bucket.resource_address() == self.nft_address, MarketError::WrongNftResource);
+        nft_bucket.non_fungible_local_ids().iter().for_each(|nft_id| self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone())));
+        let badge_bucket = self.pool_manager.mint_ruid_non_fungible(PoolBadge {
+            name: String::from("impahla pool badge"),
+            description: String::from("this badge allow you to withdraw your liquidity from the secondary market"),
+            nft_address: self.nft_address,
+            component_address: self.component_address
+          }).as_non_fungible();
+        self.pools.insert(badge_bucket.non_fungible_local_id(), Pool {
+            nft_ids: nft_bucket.non_fungible_local_ids().into_iter().collect(),
+            ccy_amount: ccy_bucket.amount(),
+            spot_price: spot_price,
+            curve: curve,
+            spread: spread
+        });
+        self.nft_vault.put(nft_bucket);
+        self.ccy_vault.put(ccy_bucket);
+        badge_bucket
+    }
+    
+    pub fn buy_from_pool(&mut self, pool_id: NonFungibleLocalId, nft_id: NonFungibleLocalId, mut ccy_bucket: FungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let pool = self.pools.get_mut(&pool_id).or_panic(MarketError::PoolNotFound);
+        let position = pool.nft_ids.iter().position(|id| *id == nft_id).or_panic(MarketError::NftNotInPool);
+        pool.nft_ids.remove(position);
+        let price = pool.spot_price * (Decimal::one() + pool.spread);
+        pool.ccy_amount += price;
+        pool.spot_price = pool.curve.up(pool.spot_price);
+        
+        let fee = price*self.fee_rate;
+        let mut bucket = self.take_payment(&mut ccy_bucket, price + fee);
+        self.fee_vault.put(bucket.take(fee));
+        self.fee_amount = self.fee_vault.amount();
+        self.ccy_vault.put(bucket);
+        (self.nft_vault.take_non_fungible(&nft_id), ccy_bucket)
+    }
+    
+    pub fn sell_to_pool(&mut self, pool_id: NonFungibleLocalId, nft_bucket: NonFungibleBucket) -> FungibleBucket {
+        ensure(!self.winding_down, MarketError::WindingDown);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let pool = self.pools.get_mut(&pool_id).or_panic(MarketError::PoolNotFound);
+        let price = pool.curve.down(pool.spot_price);
+        ensure(pool.ccy_amount >= price, MarketError::PoolOutOfCurrency);
+        pool.ccy_amount -= price;
+        pool.spot_price = price;
+        pool.nft_ids.push(nft_id);
+        
+        let mut bucket = self.ccy_vault.take(price);
+        self.fee_vault.put(bucket.take(price*self.fee_rate));
+        self.fee_amount = self.fee_vault.amount();
+        self.nft_vault.put(nft_bucket);
+        bucket
+    }
+    
+    // current (buy, sell) prices of the pool, before the platform fee
+    pub fn pool_prices(&self, pool_id: NonFungibleLocalId) -> (Decimal, Decimal) {
+        let pool = self.pools.get(&pool_id).or_panic(MarketError::PoolNotFound);
+        (pool.spot_price * (Decimal::one() + pool.spread), pool.curve.down(pool.spot_price))
+    }
+    
+    pub fn close_pool(&mut self, badge_bucket: NonFungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        ensure(badge_bucket.resource_address() == self.pool_manager.address(), MarketError::WrongBadgeResource);
+        let pool = self.pools.remove(&badge_bucket.non_fungible_local_id()).or_panic(MarketError::InvalidBadge);
+        badge_bucket.burn();
+        let mut nft_bucket = NonFungibleBucket::new(self.nft_address);
+        for nft_id in pool.nft_ids.iter() {
+            self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+            nft_bucket.put(self.nft_vault.take_non_fungible(nft_id));
+        }
+        (nft_bucket, self.ccy_vault.take(pool.ccy_amount))
+    }
+    
+    // the NFT backs a new share token, the whole supply is returned to the depositor
+    pub fn fractionalize(&mut self, nft_bucket: NonFungibleBucket, shares: Decimal, reserve_price: Decimal) -> FungibleBucket {
+        ensure(!self.winding_down, MarketError::WindingDown);
+        ensure(shares > Decimal::zero(), MarketError::InvalidAmount);
+        ensure(reserve_price >= Decimal::zero(), MarketError::NegativeCost);
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let share_bucket = ResourceBuilder::new_fungible(OwnerRole::None)
+                .metadata(metadata! {
+                    init {
+                        "name" => "Impahla NFT shares", updatable;
+                        "description" => "Fractional ownership of an NFT escrowed in the secondary market", updatable;
+                        "component" => self.component_address, locked;
+                        "nft_address" => self.nft_address, locked;
+                        "nft_id" => nft_id.clone(), locked;
+                        "tags" => vec!["utility"], updatable;
+                        "icon_url" => Url::of("https://www.impahla.io/favicon.png"), updatable;
+                        "info_url" => Url::of("https://www.impahla.io/"), updatable;
+                    }
+                })
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(self.component_address)));
+                    burner_updater => rule!(require(self.auth_vault.resource_address()));
+                })
+                .mint_initial_supply(shares);
+        self.fractions.insert(share_bucket.resource_address(), Fraction {
+            nft_id: nft_id,
+            supply: shares,
+            reserve_price: reserve_price,
+            proceeds: None
+        });
+        self.nft_vault.put(nft_bucket);
+        share_bucket
+    }
+    
+    // buys every outstanding share at the reserve price, the holders then redeem the CCY pro rata
+    pub fn buyout(&mut self, share_address: ResourceAddress, mut ccy_bucket: FungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        let fraction = self.fractions.get(&share_address).cloned().or_panic(MarketError::FractionNotFound);
+        ensure(fraction.proceeds.is_none(), MarketError::BoughtOut);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, fraction.nft_id.clone()));
+        let mut bucket = self.take_payment(&mut ccy_bucket, fraction.reserve_price);
+        self.fee_vault.put(bucket.take(fraction.reserve_price*self.fee_rate));
+        self.fee_amount = self.fee_vault.amount();
+        self.fractions.get_mut(&share_address).unwrap().proceeds = Some(bucket.amount());
+        self.ccy_vault.put(bucket);
+        (self.nft_vault.take_non_fungible(&fraction.nft_id), ccy_bucket)
+    }
+    
+    // after a buyout the shares are redeemed for their part of the CCY, before it all the shares redeem the NFT
+    pub fn redeem_shares(&mut self, share_bucket: FungibleBucket) -> (FungibleBucket, Option<NonFungibleBucket>) {
+        let share_address = share_bucket.resource_address();
+        let fraction = self.fractions.get(&share_address).cloned().or_panic(MarketError::FractionNotFound);
+        let amount = share_bucket.amount();
+        share_bucket.burn();
+        match fraction.proceeds {
+            Some(proceeds) => {
+                let payout = proceeds * amount / fraction.supply;
+                if amount == fraction.supply {
+                    self.fractions.remove(&share_address);
+                } else {
+                    let fraction = self.fractions.get_mut(&share_address).unwrap();
+                    fraction.supply -= amount;
+                    fraction.proceeds = Some(proceeds - payout);
+                }
+                (self.ccy_vault.take(payout), None)
+            },
+            None => {
+                ensure(amount == fraction.supply, MarketError::PartialShares);
+                self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, fraction.nft_id.clone()));
+                self.fractions.remove(&share_address);
+                (FungibleBucket::new(self.ccy_address), Some(self.nft_vault.take_non_fungible(&fraction.nft_id)))
+            }
+        }
+    }
+    
+    // without constraints, the bid accepts any NFT of the collection
+    pub fn place_trait_bid(&mut self, ccy_bucket: FungibleBucket, constraints: Vec<TraitConstraint>) -> NonFungibleBucket {
+        self.check_buyer();
+        ensure(!self.winding_down, MarketError::WindingDown);
+        ensure(ccy_bucket.amount() > Decimal::zero(), MarketError::InvalidAmount);
+        for constraint in constraints.iter() {
+            ensure(self.trait_fields.contains(&constraint.field), MarketError::UnknownTraitField);
+        }
+        let receipt_bucket = self.mint_bid_receipt(None);
+        self.trait_bids.insert(receipt_bucket.non_fungible_local_id(), (ccy_bucket.amount(), constraints));
+        self.ccy_vault.put(ccy_bucket);
+        receipt_bucket
+    }
+    
+    // the bidder claims the NFT with its bid receipt
+    pub fn fill_trait_bid(&mut self, bid_id: NonFungibleLocalId, nft_bucket: NonFungibleBucket) -> FungibleBucket {
+        let nft_id = Self::single_id(&nft_bucket, self.nft_address, MarketError::WrongNftResource);
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        let (price, constraints) = self.trait_bids.get(&bid_id).cloned().or_panic(MarketError::BidNotFound);
+        let data = ResourceManager::from(self.nft_address).get_non_fungible_data::<RawNonFungibleData>(&nft_id);
+        ensure(constraints.iter().all(|constraint| self.matches_trait(&data.0, constraint)), MarketError::TraitMismatch);
+        self.trait_bids.remove(&bid_id);
+        
+        let mut bucket = self.ccy_vault.take(price);
+        let fee = price*self.fee_rate;
+        self.fee_vault.put(bucket.take(fee));
+        self.fee_amount = self.fee_vault.amount();
+        self.bid_claims.insert(bid_id, (dec!(0), Some(nft_id)));
+        self.nft_vault.put(nft_bucket);
+        bucket
+    }
+    
+    pub fn cancel_trait_bid(&mut self, receipt_bucket: NonFungibleBucket) -> FungibleBucket {
+        ensure(receipt_bucket.resource_address() == self.bid_manager.address(), MarketError::WrongReceiptResource);
+        let (price, _) = self.trait_bids.remove(&receipt_bucket.non_fungible_local_id()).or_panic(MarketError::BidNotFound);
+        receipt_bucket.burn();
+        self.ccy_vault.take(price)
+    }
+    
+    pub fn set_trait_fields(&mut self, fields: Vec<String>) {
+        self.trait_fields = fields;
+    }
+    
+    pub fn allow_bundle_collection(&mut self, nft_address: ResourceAddress) {
+        self.bundle_collections.insert(nft_address);
+    }
+    
+    pub fn last_sale_price(&self) -> Option<Decimal> {
+        self.ordered_sales().last().map(|(_, price)| *price)
+    }
+    
+    // lowest live fixed price listing
+    pub fn floor_price(&self) -> Option<Decimal> {
+        self.offers.values()
+            .filter(|(badge_id, _, starts_at)| starts_at.map_or(true, |start| Self::is_past(start)) && self.listing(badge_id).1 == ListingState::Active && !self.listing_currencies.contains_key(badge_id))
+            .map(|(_, cost, _)| *cost)
+            .min()
+    }
+    
+    // average of the last sale price over the window, weighted by how long each price held
+    pub fn twap(&self, window_seconds: i64) -> Option<Decimal> {
+        ensure(window_seconds > 0, MarketError::InvalidWindow);
+        let sales = self.ordered_sales();
+        let now = Clock::current_time_rounded_to_minutes().seconds_since_unix_epoch;
+        let start = now - window_seconds;
+        let mut weighted = dec!(0);
+        let mut covered = 0i64;
+        for (i, (time, price)) in sales.iter().enumerate() {
+            let from = time.seconds_since_unix_epoch.max(start);
+            let to = sales.get(i + 1).map_or(now, |(next, _)| next.seconds_since_unix_epoch);
+            if to > from {
+                weighted += *price * Decimal::from(to - from);
+                covered += to - from;
+            }
+        }
+        if covered == 0 {
+            return self.last_sale_price();
+        }
+        Some(weighted / Decimal::from(covered))
+    }
+    
+    pub fn sales_history(&self, nft_id: NonFungibleLocalId, offset: usize, limit: usize) -> Vec<SaleRecord> {
+        match self.history.get(&nft_id) {
+            Some(records) => records.iter().skip(offset).take(limit).cloned().collect(),
+            None => Vec::new()
+        }
+    }
+    
+    pub fn listing_state(&self, badge_id: NonFungibleLocalId) -> ListingState {
+        self.listing(&badge_id).1
+    }
+    
+    // unclaimed rewards left here at migration, only taken by the successor
+    pub fn take_rewards(&mut self, key: NonFungibleGlobalId) -> Decimal {
+        Runtime::assert_access_rule(self.successor.map_or(rule!(deny_all), |successor| rule!(require(global_caller(successor)))));
+        self.take_rewards_of(&key)
+    }
+    
+    // nft id and state of the listing of the badge, also read by the successor
+    pub fn badge_listing(&self, badge_id: NonFungibleLocalId) -> (NonFungibleLocalId, ListingState) {
+        self.listing(&badge_id)
+    }
+    
+    // badge id, cost and start time of a fixed price listing
+    pub fn offer(&self, nft_id: NonFungibleLocalId) -> Option<(NonFungibleLocalId, Decimal, Option<Instant>)> {
+        self.offers.get(&nft_id).cloned()
+    }
+    
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+    
+    pub fn previous(&self) -> Option<ComponentAddress> {
+        self.previous
+    }
+    
+    pub fn successor(&self) -> Option<ComponentAddress> {
+        self.successor
+    }
+    
+    pub fn collect_fees(&mut self) -> FungibleBucket {
+        self.fee_amount = dec!(0);
+        self.fee_vault.take_all()
+    }
+    
+    // fees of the sales priced in another currency
+    pub fn collect_currency_fees(&mut self, currency: ResourceAddress) -> FungibleBucket {
+        self.currency_vaults.get_mut(&currency).or_panic(MarketError::NothingToCollect).1.take_all()
+    }
+    
+    pub fn set_buyer_rule(&mut self, buyer_rule: Option<AccessRule>) {
+        self.buyer_rule = buyer_rule;
+    }
+    
+    // rewards of the NFTs of the proof: a seller badge or the NFT the buyer identified with
+    pub fn claim_rewards(&mut self, proof: NonFungibleProof) -> FungibleBucket {
+        let proof = proof.skip_checking();
+        let address = proof.resource_address();
+        let mut amount = dec!(0);
+        for id in proof.non_fungible_local_ids() {
+            amount += self.take_rewards_of(&NonFungibleGlobalId::new(address, id));
+        }
+        ensure(amount > Decimal::zero(), MarketError::NothingToClaim);
+        self.reward_manager.mint(amount).as_fungible()
+    }
+    
+    // anyone pushes the rewards of a seller account registered at sell to it
+    pub fn claim_account_rewards(&mut self, mut account: Global<Account>) {
+        let amount = self.account_rewards.remove(&account.address()).or_panic(MarketError::NothingToClaim);
+        account.try_deposit_or_abort(self.reward_manager.mint(amount), None);
+    }
+    
+    // replaces the rate of an existing entry
+    pub fn set_discount(&mut self, resource: ResourceAddress, min_amount: Decimal, fee_rate: Decimal) {
+        ensure(fee_rate >= Decimal::zero() && fee_rate <= Decimal::one(), MarketError::InvalidRate);
+        self.remove_discount(resource, min_amount);
+        self.discounts.push((resource, min_amount, fee_rate));
+    }
+    
+    pub fn remove_discount(&mut self, resource: ResourceAddress, min_amount: Decimal) {
+        self.discounts.retain(|(address, amount, _)| *address != resource || *amount != min_amount);
+    }
+    
+    // the token cannot change once staked, the share can
+    pub fn set_staking(&mut self, token: ResourceAddress, staker_share: Decimal) {
+        ensure(staker_share >= Decimal::zero() && staker_share <= Decimal::one(), MarketError::InvalidRate);
+        match &self.stake_vault {
+            Some(vault) => ensure(vault.resource_address() == token, MarketError::WrongStakeToken),
+            None => self.stake_vault = Some(FungibleVault::new(token))
+        }
+        self.staker_share = staker_share;
+    }
+    
+    pub fn stake(&mut self, token_bucket: FungibleBucket) -> NonFungibleBucket {
+        let vault = self.stake_vault.as_ref().or_panic(MarketError::StakingDisabled);
+        ensure(token_bucket.resource_address() == vault.resource_address(), MarketError::WrongStakeToken);
+        let amount = token_bucket.amount();
+        ensure(amount > Decimal::zero(), MarketError::InvalidAmount);
+        let receipt_bucket = self.stake_manager.mint_ruid_non_fungible(StakeReceipt {
+            name: String::from("impahla stake receipt"),
+            description: String::from("this receipt allow you to claim your part of the fees and to unstake"),
+            token: token_bucket.resource_address(),
+            amount: amount,
+            component_address: self.component_address
+          }).as_non_fungible();
+        self.stakes.insert(receipt_bucket.non_fungible_local_id(), (amount, self.reward_per_share));
+        self.stake_vault.as_mut().unwrap().put(token_bucket);
+        receipt_bucket
+    }
+    
+    // the pending fees are paid with the tokens
+    pub fn unstake(&mut self, receipt_bucket: NonFungibleBucket) -> (FungibleBucket, FungibleBucket) {
+        let receipt_id = Self::single_id(&receipt_bucket, self.stake_manager.address(), MarketError::WrongReceiptResource);
+        let ccy_bucket = self.take_stake_rewards(&receipt_id);
+        let (amount, _) = self.stakes.remove(&receipt_id).unwrap();
+        receipt_bucket.burn();
+        (self.stake_vault.as_mut().unwrap().take(amount), ccy_bucket)
+    }
+    
+    pub fn claim_stake_rewards(&mut self, receipt_bucket: NonFungibleBucket) -> (NonFungibleBucket, FungibleBucket) {
+        let receipt_id = Self::single_id(&receipt_bucket, self.stake_manager.address(), MarketError::WrongReceiptResource);
+        let ccy_bucket = self.take_stake_rewards(&receipt_id);
+        (receipt_bucket, ccy_bucket)
+    }
+    
+    pub fn set_reward_rates(&mut self, buyer_rate: Decimal, seller_rate: Decimal) {
+        ensure(buyer_rate >= Decimal::zero() && seller_rate >= Decimal::zero(), MarketError::NegativeRewardRate);
+        self.reward_rates = (buyer_rate, seller_rate);
+    }
+    
+    pub fn set_reward_cap(&mut self, reward_cap: Decimal) {
+        self.reward_cap = reward_cap;
+    }
+    
+    // component exposing `random(seed) -> u64` for the raffle draws, None for RuidSource
+    pub fn set_randomness(&mut self, randomness: Option<ComponentAddress>) {
+        self.randomness = randomness;
+    }
+    
+    // an NFT of the market collection is flagged with the nft address of the market
+    pub fn flag(&mut self, item: NonFungibleGlobalId) {
+        self.flagged.insert(item.clone());
+        Runtime::emit_event(FlaggedEvent { item: item, flagged: true });
+    }
+    
+    pub fn unflag(&mut self, item: NonFungibleGlobalId) {
+        self.flagged.remove(&item);
+        Runtime::emit_event(FlaggedEvent { item: item, flagged: false });
+    }
+    
+    pub fn is_flagged(&self, item: NonFungibleGlobalId) -> bool {
+        self.flagged.contains(&item)
+    }
+    
+    // closes the frozen listing of a flagged NFT and gives the NFT, or all the items of its bundle, to the moderator
+    // to be returned to their rightful owners. The bidders, ticket holders and paying buyers claim their payment back
+    // with their bid receipt, the income already earned by the listing is still collected with its badge. A loaned,
+    // pooled or fractionalized NFT cannot be seized, it stays frozen in the market until unflagged.
+    pub fn seize_flagged(&mut self, item: NonFungibleGlobalId) -> Vec<NonFungibleBucket> {
+        ensure(self.flagged.remove(&item), MarketError::NotFlagged);
+        Runtime::emit_event(FlaggedEvent { item: item.clone(), flagged: false });
+        let bundle_id = self.bundles.iter()
+            .find(|(_, (items, _))| items.contains(&item))
+            .map(|(badge_id, _)| badge_id.clone());
+        if let Some(badge_id) = bundle_id {
+            let (items, _) = self.bundles.remove(&badge_id).unwrap();
+            self.set_state(&badge_id, ListingState::Cancelled);
+            return items.iter().map(|item| self.take_item(item)).collect();
+        }
+        ensure(item.resource_address() == self.nft_address, MarketError::NotListed);
+        let nft_id = item.local_id().clone();
+        let badge_id = self.listing_badge(&nft_id).or_panic(MarketError::NotListed);
+        ensure(self.listing(&badge_id).1 != ListingState::Sold, MarketError::NotListed);
+        self.close_seized(&nft_id);
+        if self.to_collect.get(&badge_id) == Some(&dec!(0)) {
+            self.to_collect.remove(&badge_id);
+        }
+        self.set_state(&badge_id, ListingState::Cancelled);
+        vec![self.nft_vault.take_non_fungible(&nft_id)]
+    }
+    
+    // stops new listings, buys, pools, fractions, loan offers and trait bids, the ongoing auctions, loans, rentals
+    // and claims carry on
+    pub fn wind_down(&mut self) {
+        self.winding_down = true;
+    }
+    
+    // pushes the NFT of the idle listings or the proceeds of the sold ones to the registered accounts, by batches
+    // of limit examined listings, each call resuming after the last one examined. A busy listing stays registered
+    // for a later round, an account that refused a deposit is not tried again: its badge holder can still cancel
+    // or collect as usual. Returns the number of registered listings left.
+    pub fn rescue(&mut self, limit: usize) -> usize {
+        ensure(self.winding_down, MarketError::NotWindingDown);
+        let mut registered: Vec<(NonFungibleLocalId, Global<Account>)> = self.refund_accounts.iter()
+            .filter(|(_, account)| !self.refused_accounts.contains(&account.address()))
+            .map(|(badge_id, account)| (badge_id.clone(), account.clone()))
+            .collect();
+        registered.sort_by(|a, b| a.0.cmp(&b.0));
+        // starts after the cursor, then wraps around
+        let start = match &self.rescue_cursor {
+            Some(cursor) => registered.iter().position(|(badge_id, _)| badge_id > cursor).unwrap_or(0),
+            None => 0
+        };
+        registered.rotate_left(start);
+        registered.truncate(limit);
+        for (badge_id, mut account) in registered {
+            self.rescue_cursor = Some(badge_id.clone());
+            if self.refused_accounts.contains(&account.address()) {
+                continue;
+            }
+            let (nft_id, state) = self.listing(&badge_id);
+            let done = if state == ListingState::Cancelled || (state == ListingState::Sold && !self.to_collect.contains_key(&badge_id)) {
+                true // already cancelled or collected
+            } else if state == ListingState::Sold {
+                let (ccy_bucket, nft_bucket) = self.take_proceeds(&badge_id);
+                let amount = ccy_bucket.amount();
+                let mut buckets: Vec<Bucket> = vec![ccy_bucket.into()];
+                buckets.extend(nft_bucket.map(|bucket| bucket.into()));
+                match account.try_deposit_batch_or_refund(buckets, None) {
+                    Some(mut refunded) => {
+                        if refunded.len() > 1 {
+                            let nft_bucket = refunded.pop().unwrap().as_non_fungible();
+                            self.nft_to_collect.insert(badge_id.clone(), NonFungibleGlobalId::new(nft_bucket.resource_address(), nft_bucket.non_fungible_local_id()));
+                            self.put_items(nft_bucket);
+                        }
+                        self.proceeds_vault(&badge_id).put(refunded.pop().unwrap().as_fungible());
+                        self.credit(&badge_id, amount);
+                        self.refused_accounts.insert(account.address());
+                        false
+                    }
+                    None => true
+                }
+            } else if self.is_idle(&badge_id, &nft_id) {
+                // forfeited layaway deposits
+                let amount = self.to_collect.get(&badge_id).cloned().unwrap_or(dec!(0));
+                let buckets: Vec<Bucket> = vec![self.nft_vault.take_non_fungible(&nft_id).into(), self.ccy_vault.take(amount).into()];
+                match account.try_deposit_batch_or_refund(buckets, None) {
+                    Some(mut refunded) => {
+                        self.ccy_vault.put(refunded.pop().unwrap().as_fungible());
+                        self.nft_vault.put(refunded.pop().unwrap().as_non_fungible());
+                        self.refused_accounts.insert(account.address());
+                        false
+                    }
+                    None => {
+                        self.to_collect.remove(&badge_id);
+                        self.remove_listing(&nft_id);
+                        self.set_state(&badge_id, ListingState::Cancelled);
+                        true
+                    }
+                }
+            } else {
+                false // busy
+            };
+            if done {
+                self.refund_accounts.remove(&badge_id);
+            }
+        }
+        self.refund_accounts.len()
+    }
+    
+    // moves the listings, vaults and claimables to a new component at STATE_VERSION, which mints and burns
+    // the market resources from then on: the badges and receipts keep working there. The sales history,
+    // the listing states (read by the successor until they change) and the unclaimed trading rewards stay here, the successor takes them on collect and claim_rewards. The arbiter and the moderator are reset to the fee owner.
+    // The successor is built from this same package: there is no upgrade path to a new package yet.
+    pub fn migrate(&mut self) -> Global<NftSecondaryMarket> {
+        ensure(self.successor.is_none(), MarketError::Migrated);
+        let (address_reservation, component_address) = Runtime::allocate_component_address(NftSecondaryMarket::blueprint_id());
+        let new_caller = rule!(require(global_caller(component_address)));
+        self.auth_vault.authorize_with_amount(dec!(1), || {
+            for manager in [self.resource_manager, self.loan_manager, self.bid_manager, self.pool_manager, self.stake_manager] {
+                manager.set_mintable(new_caller.clone());
+                manager.set_burnable(new_caller.clone());
+            }
+            self.rental_manager.set_mintable(new_caller.clone());
+            self.resource_manager.set_updatable_non_fungible_data(new_caller.clone());
+            self.reward_manager.set_mintable(rule!(require(global_caller(component_address)) || require(global_caller(self.component_address))));
+            for share_address in self.fractions.keys() {
+                ResourceManager::from(*share_address).set_burnable(new_caller.clone());
+            }
+        });
+        self.successor = Some(component_address);
+        let extra_vaults = self.extra_vaults.iter_mut()
+            .map(|(address, vault)| (*address, NonFungibleVault::with_bucket(vault.take_all())))
+            .collect();
+        let currency_vaults = self.currency_vaults.iter_mut()
+            .map(|(address, (proceeds, fees))| (*address, (FungibleVault::with_bucket(proceeds.take_all()), FungibleVault::with_bucket(fees.take_all()))))
+            .collect();
+        let component = Self {
+                nft_vault: NonFungibleVault::with_bucket(self.nft_vault.take_all()),
+                ccy_vault: FungibleVault::with_bucket(self.ccy_vault.take_all()),
+                resource_manager: self.resource_manager,
+                nft_address: self.nft_address,
+                ccy_address: self.ccy_address,
+                badge_address: self.badge_address,
+                badges: KeyValueStore::new(),
+                offers: std::mem::take(&mut self.offers),
+                auctions: std::mem::take(&mut self.auctions),
+                to_collect: std::mem::take(&mut self.to_collect),
+                layaway_terms: std::mem::take(&mut self.layaway_terms),
+                layaways: std::mem::take(&mut self.layaways),
+                escrow_terms: std::mem::take(&mut self.escrow_terms),
+                escrows: std::mem::take(&mut self.escrows),
+                raffles: std::mem::take(&mut self.raffles),
+                randomness: self.randomness,
+                swaps: std::mem::take(&mut self.swaps),
+                extra_vaults: extra_vaults,
+                listing_currencies: std::mem::take(&mut self.listing_currencies),
+                currency_vaults: currency_vaults,
+                nft_to_collect: std::mem::take(&mut self.nft_to_collect),
+                bundles: std::mem::take(&mut self.bundles),
+                bundle_collections: std::mem::take(&mut self.bundle_collections),
+                rentals: std::mem::take(&mut self.rentals),
+                rental_manager: self.rental_manager,
+                loan_offers: std::mem::take(&mut self.loan_offers),
+                loans: std::mem::take(&mut self.loans),
+                loan_manager: self.loan_manager,
+                sealed_auctions: std::mem::take(&mut self.sealed_auctions),
+                sealed_bids: std::mem::take(&mut self.sealed_bids),
+                bid_claims: std::mem::take(&mut self.bid_claims),
+                bid_manager: self.bid_manager,
+                pools: std::mem::take(&mut self.pools),
+                pool_manager: self.pool_manager,
+                fractions: std::mem::take(&mut self.fractions),
+                trait_bids: std::mem::take(&mut self.trait_bids),
+                trait_fields: self.trait_fields.clone(),
+                component_address: component_address,
+                fee_badge: self.fee_badge,
+                fee_rate: self.fee_rate,
+                fee_vault: FungibleVault::with_bucket(self.fee_vault.take_all()),
+                fee_amount: self.fee_amount,
+                discounts: self.discounts.clone(),
+                sales: self.sales.clone(),
+                sales_head: self.sales_head,
+                history: KeyValueStore::new(),
+                buyer_rule: self.buyer_rule.clone(),
+                reward_manager: self.reward_manager,
+                reward_rates: self.reward_rates,
+                reward_cap: self.reward_cap,
+                rewards_accrued: self.rewards_accrued,
+                rewards: KeyValueStore::new(),
+                account_rewards: KeyValueStore::new(),
+                stake_vault: self.stake_vault.as_mut().map(|vault| FungibleVault::with_bucket(vault.take_all())),
+                stake_manager: self.stake_manager,
+                staker_share: self.staker_share,
+                reward_per_share: self.reward_per_share,
+                stakes: std::mem::take(&mut self.stakes),
+                version: STATE_VERSION,
+                auth_vault: FungibleVault::with_bucket(self.auth_vault.take_all()),
+                previous: Some(self.component_address),
+                successor: None,
+                refund_accounts: std::mem::take(&mut self.refund_accounts),
+                winding_down: self.winding_down,
+                rescue_cursor: self.rescue_cursor.take(),
+                refused_accounts: std::mem::take(&mut self.refused_accounts),
+                flagged: std::mem::take(&mut self.flagged),
+            }.instantiate();
+        self.fee_amount = dec!(0);
+        component.prepare_to_globalize(OwnerRole::None)
+                 .roles(roles! {
+                   fee_owner => rule!(require(self.fee_badge));
+                   arbiter => rule!(require(self.fee_badge));
+                   moderator => rule!(require(self.fee_badge));
+                 })
+                 .with_address(address_reservation)
+                 .globalize()
+    }
+    
+    fn record_sale(&mut self, price: Decimal) {
+        let sale = (Clock::current_time_rounded_to_minutes(), price);
+        if self.sales.len() < SALES_CAPACITY {
+            self.sales.push(sale);
+        } else {
+            self.sales[self.sales_head] = sale;
+            self.sales_head = (self.sales_head + 1) % SALES_CAPACITY;
+        }
+    }
+    
+    fn matches_trait(&self, data: &ScryptoValue, constraint: &TraitConstraint) -> bool {
+        // the fields may have changed since the bid
+        let index = self.trait_fields.iter().position(|field| *field == constraint.field).or_panic(MarketError::UnknownTraitField);
+        let field = match data {
+            ScryptoValue::Tuple { fields } => fields.get(index),
+            _ => None
+        };
+        let text = match field {
+            Some(ScryptoValue::String { value }) => value.clone(),
+            Some(ScryptoValue::Bool { value }) => value.to_string(),
+            Some(ScryptoValue::U8 { value }) => value.to_string(),
+            Some(ScryptoValue::U16 { value }) => value.to_string(),
+            Some(ScryptoValue::U32 { value }) => value.to_string(),
+            Some(ScryptoValue::U64 { value }) => value.to_string(),
+            Some(ScryptoValue::I8 { value }) => value.to_string(),
+            Some(ScryptoValue::I16 { value }) => value.to_string(),
+            Some(ScryptoValue::I32 { value }) => value.to_string(),
+            Some(ScryptoValue::I64 { value }) => value.to_string(),
+            Some(ScryptoValue::Custom { value: ScryptoCustomValue::Decimal(value) }) => value.to_string(),
+            _ => return false
+        };
+        text == constraint.value
+    }
+    
+    fn record_history(&mut self, nft_id: &NonFungibleLocalId, price: Decimal, fee: Decimal, seller_badge: &NonFungibleLocalId, buyer: Option<NonFungibleGlobalId>) {
+        let mut records = self.history.get(nft_id).map(|records| records.clone()).unwrap_or_default();
+        if records.len() == HISTORY_LENGTH {
+            records.remove(0);
+        }
+        records.push(SaleRecord {
+            price: price,
+            fee: fee,
+            time: Clock::current_time_rounded_to_minutes(),
+            seller_badge: seller_badge.clone(),
+            buyer: buyer
+        });
+        self.history.insert(nft_id.clone(), records);
+    }
+    
+    // sales from the oldest to the latest
+    fn ordered_sales(&self) -> Vec<(Instant, Decimal)> {
+        let (latest, oldest) = self.sales.split_at(self.sales_head);
+        oldest.iter().chain(latest.iter()).cloned().collect()
+    }
+    
+    // id of the single item of the bucket, checking its resource
+    fn single_id(bucket: &NonFungibleBucket, address: ResourceAddress, error: MarketError) -> NonFungibleLocalId {
+        ensure(bucket.resource_address() == address, error);
+        ensure(bucket.amount() == dec!(1), MarketError::SingleItemExpected);
+        bucket.non_fungible_local_id()
+    }
+    
+    fn take_payment(&self, ccy_bucket: &mut FungibleBucket, cost: Decimal) -> FungibleBucket {
+        ensure(ccy_bucket.resource_address() == self.ccy_address, MarketError::WrongCurrency);
+        ensure(ccy_bucket.amount() >= cost, MarketError::InsufficientPayment);
+        ccy_bucket.take(cost)
+    }
+    
+    // sale of a listing priced in another currency: its fee is kept apart and it feeds neither the rewards, the stakers nor the sales history
+    fn buy_in_currency(&mut self, nft_id: &NonFungibleLocalId, badge_id: &NonFungibleLocalId, currency: ResourceAddress, cost: Decimal, mut ccy_bucket: FungibleBucket, fee_rate: Decimal) -> (NonFungibleBucket, FungibleBucket) {
+        ensure(ccy_bucket.resource_address() == currency, MarketError::WrongCurrency);
+        ensure(ccy_bucket.amount() >= cost, MarketError::InsufficientPayment);
+        let mut bucket = ccy_bucket.take(cost);
+        let (proceeds, fees) = self.currency_vaults.get_mut(&currency).unwrap();
+        fees.put(bucket.take(cost*fee_rate));
+        let amount = bucket.amount();
+        proceeds.put(bucket);
+        self.credit(badge_id, amount);
+        self.set_state(badge_id, ListingState::Sold);
+        (self.nft_vault.take_non_fungible(nft_id), ccy_bucket)
+    }
+    
+    // vault of the proceeds of a listing, in its currency
+    fn proceeds_vault(&mut self, badge_id: &NonFungibleLocalId) -> &mut FungibleVault {
+        match self.listing_currencies.get(badge_id) {
+            Some(currency) => &mut self.currency_vaults.get_mut(currency).unwrap().0,
+            None => &mut self.ccy_vault
+        }
+    }
+    
+    fn is_past(instant: Instant) -> bool {
+        Clock::current_time_is_at_or_after(instant, TimePrecision::Minute)
+    }
+    
+    fn put_items(&mut self, nft_bucket: NonFungibleBucket) {
+        let address = nft_bucket.resource_address();
+        if address == self.nft_address {
+            self.nft_vault.put(nft_bucket);
+        } else {
+            self.extra_vaults.entry(address)
+                .or_insert_with(|| NonFungibleVault::new(address))
+                .put(nft_bucket);
+        }
+    }
+    
+    fn take_item(&mut self, item: &NonFungibleGlobalId) -> NonFungibleBucket {
+        if item.resource_address() == self.nft_address {
+            self.nft_vault.take_non_fungible(item.local_id())
+        } else {
+            self.extra_vaults.get_mut(&item.resource_address()).unwrap().take_non_fungible(item.local_id())
+        }
+    }
+    
+    fn mint_loan_badge(&self, name: &str, amount: Decimal, interest: Decimal, duration_days: u64) -> NonFungibleBucket {
+        self.loan_manager.mint_ruid_non_fungible(LoanBadge {
+            name: String::from(name),
+            description: String::from("this badge allow you to interact with your loan in the secondary market"),
+            nft_address: self.nft_address,
+            amount: amount,
+            interest: interest,
+            duration_days: duration_days,
+            component_address: self.component_address
+          }).as_non_fungible()
+    }
+    
+    // buy now ends the auction until a bid reaches its price, the highest bidder is refunded
+    fn take_buy_now(&mut self, nft_id: &NonFungibleLocalId) -> (NonFungibleLocalId, Decimal) {
+        let (badge_id, terms, highest) = self.auctions.remove(nft_id).or_panic(MarketError::NotListed);
+        ensure(!Self::is_past(terms.end_time), MarketError::AuctionEnded);
+        let buy_now_price = terms.buy_now_price.or_panic(MarketError::NoBuyNowPrice);
+        ensure(highest.as_ref().map_or(true, |(_, amount)| *amount < buy_now_price), MarketError::BuyNowOutbid);
+        if let Some((receipt_id, amount)) = highest {
+            self.bid_claims.insert(receipt_id, (amount, None));
+        }
+        (badge_id, buy_now_price)
+    }
+    
+    fn mint_bid_receipt(&self, nft_id: Option<NonFungibleLocalId>) -> NonFungibleBucket {
+        self.bid_manager.mint_ruid_non_fungible(BidReceipt {
+            name: String::from("impahla bid receipt"),
+            description: String::from("this receipt allow you to claim the refund or the NFT of your bid"),
+            nft_address: self.nft_address,
+            nft_id: nft_id,
+            component_address: self.component_address
+          }).as_non_fungible()
+    }
+    
+    fn mint_badge(&self, nft: &NonFungibleGlobalId, terms: Option<&AuctionTerms>, starts_at: Option<Instant>) -> NonFungibleBucket {
+        ensure(!self.winding_down, MarketError::WindingDown);
+        self.ensure_not_flagged(nft);
+        self.resource_manager.mint_ruid_non_fungible(Badge {
+            name: String::from("impahla seller badge"),
+            description: String::from("this badge allow you to interact with your offer in the secondary market"),
+            nft_address: nft.resource_address(),
+            nft_id: nft.local_id().clone(),
+            component_address: self.component_address,
+            reserve_price: terms.filter(|terms| terms.public_reserve).map(|terms| terms.reserve_price),
+            buy_now_price: terms.and_then(|terms| terms.buy_now_price),
+            end_time: terms.map(|terms| terms.end_time),
+            starts_at: starts_at
+          }).as_non_fungible()
+    }
+    
+    fn check_auction_terms(terms: &AuctionTerms) {
+        ensure(terms.start_price >= Decimal::zero() && terms.reserve_price >= Decimal::zero(), MarketError::NegativeCost);
+        ensure(terms.buy_now_price.map_or(true, |price| price >= terms.start_price), MarketError::InvalidBuyNowPrice);
+        ensure(terms.extension_minutes >= 0, MarketError::InvalidExtension);
+        ensure(!Self::is_past(terms.end_time), MarketError::InvalidSchedule);
+    }
+    
+    // drops the single NFT listing, an auction only without bids
+    fn remove_listing(&mut self, nft_id: &NonFungibleLocalId) {
+        self.ensure_unlocked(nft_id);
+        self.layaway_terms.remove(nft_id);
+        self.escrow_terms.remove(nft_id);
+        if let Some(auction) = self.sealed_auctions.remove(nft_id) {
+            ensure(auction.bids.is_empty() || auction.settled, MarketError::AuctionHasBids);
+        } else if let Some((_, _, highest)) = self.auctions.remove(nft_id) {
+            ensure(highest.is_none(), MarketError::AuctionHasBids);
+        } else if let Some(raffle) = self.raffles.remove(nft_id) {
+            ensure(raffle.tickets.is_empty(), MarketError::RaffleHasTickets);
+        } else if self.offers.remove(nft_id).is_none() {
+            self.swaps.remove(nft_id).or_panic(MarketError::ListingClosed);
+        }
+    }
+    
+    // closes a seized listing whatever it is busy with, the payments held for it become claimable with the bid receipts
+    fn close_seized(&mut self, nft_id: &NonFungibleLocalId) {
+        if let Some((_, Some(receipt_id))) = self.layaway_terms.remove(nft_id) {
+            let layaway = self.layaways.remove(&receipt_id).unwrap();
+            self.bid_claims.insert(receipt_id, (layaway.paid, None));
+        }
+        if let Some((_, Some(receipt_id))) = self.escrow_terms.remove(nft_id) {
+            let escrow = self.escrows.remove(&receipt_id).unwrap();
+            self.bid_claims.insert(receipt_id, (escrow.cost, None));
+        }
+        if let Some((_, _, Some((receipt_id, amount)))) = self.auctions.remove(nft_id) {
+            self.bid_claims.insert(receipt_id, (amount, None));
+        }
+        if let Some(auction) = self.sealed_auctions.remove(nft_id) {
+            for receipt_id in auction.bids {
+                if let Some((_, _, deposit, _)) = self.sealed_bids.remove(&receipt_id) {
+                    self.bid_claims.insert(receipt_id, (deposit, None));
+                }
+            }
+        }
+        if let Some(raffle) = self.raffles.remove(nft_id) {
+            for receipt_id in raffle.tickets {
+                self.bid_claims.insert(receipt_id, (raffle.ticket_price, None));
+            }
+        }
+        self.offers.remove(nft_id);
+        self.swaps.remove(nft_id);
+        self.rentals.remove(nft_id);
+    }
+    
+    // a single NFT listing that cancel closes without affecting a buyer, a bidder or the moderator
+    fn is_idle(&self, badge_id: &NonFungibleLocalId, nft_id: &NonFungibleLocalId) -> bool {
+        let locked = self.layaway_terms.get(nft_id).map_or(false, |(_, receipt_id)| receipt_id.is_some())
+            || self.escrow_terms.get(nft_id).map_or(false, |(_, receipt_id)| receipt_id.is_some());
+        let has_bids = self.auctions.get(nft_id).map_or(false, |(_, _, highest)| highest.is_some())
+            || self.sealed_auctions.get(nft_id).map_or(false, |auction| !auction.bids.is_empty() && !auction.settled)
+            || self.raffles.get(nft_id).map_or(false, |raffle| !raffle.tickets.is_empty());
+        let flagged = self.flagged.contains(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        !locked && !has_bids && !flagged && !self.bundles.contains_key(badge_id) && !self.rentals.contains_key(nft_id)
+    }
+    
+    // badge of the single NFT listing, whatever its form
+    fn listing_badge(&self, nft_id: &NonFungibleLocalId) -> Option<NonFungibleLocalId> {
+        let layaway = self.layaway_terms.get(nft_id).and_then(|(_, receipt_id)| receipt_id.as_ref())
+            .and_then(|receipt_id| self.layaways.get(receipt_id))
+            .map(|layaway| layaway.badge_id.clone());
+        let escrow = self.escrow_terms.get(nft_id).and_then(|(_, receipt_id)| receipt_id.as_ref())
+            .and_then(|receipt_id| self.escrows.get(receipt_id))
+            .map(|escrow| escrow.badge_id.clone());
+        self.offers.get(nft_id).map(|(badge_id, _, _)| badge_id.clone())
+            .or_else(|| self.auctions.get(nft_id).map(|(badge_id, _, _)| badge_id.clone()))
+            .or_else(|| self.swaps.get(nft_id).map(|(badge_id, _, _)| badge_id.clone()))
+            .or_else(|| self.raffles.get(nft_id).map(|raffle| raffle.badge_id.clone()))
+            .or_else(|| self.sealed_auctions.get(nft_id).map(|auction| auction.badge_id.clone()))
+            .or_else(|| self.rentals.get(nft_id).map(|(badge_id, _, _, _)| badge_id.clone()))
+            .or(layaway)
+            .or(escrow)
+    }
+    
+    // a bundle badge, or the badge of another collection item with the same local id, does not own the listing
+    fn ensure_listed_by(&self, badge_id: &NonFungibleLocalId, nft_id: &NonFungibleLocalId) {
+        ensure(!self.bundles.contains_key(badge_id) && self.listing_badge(nft_id).as_ref() == Some(badge_id), MarketError::InvalidBadge);
+    }
+    
+    fn ensure_not_flagged(&self, item: &NonFungibleGlobalId) {
+        ensure(!self.flagged.contains(item), MarketError::Flagged);
+    }
+    
+    // frozen while flagged, a listing is only seized or unflagged by the moderator
+    fn ensure_unlocked(&self, nft_id: &NonFungibleLocalId) {
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, nft_id.clone()));
+        ensure(self.layaway_terms.get(nft_id).map_or(true, |(_, receipt_id)| receipt_id.is_none()), MarketError::LayawayOngoing);
+        ensure(self.escrow_terms.get(nft_id).map_or(true, |(_, receipt_id)| receipt_id.is_none()), MarketError::EscrowOngoing);
+    }
+    
+    // lowest rate of the discounts the proof qualifies for, never above fee_rate
+    fn discounted_rate(&self, discount_proof: Option<Proof>) -> Decimal {
+        let proof = match discount_proof {
+            Some(proof) => proof.skip_checking(),
+            None => return self.fee_rate
+        };
+        let (address, held) = (proof.resource_address(), proof.amount());
+        self.discounts.iter()
+            .filter(|(resource, min_amount, _)| *resource == address && held >= *min_amount)
+            .map(|(_, _, rate)| *rate)
+            .fold(self.fee_rate, |best, rate| best.min(rate))
+    }
+    
+    // trading rewards of a fixed price sale, the seller ones go to the registered account if any
+    fn accrue_sale_rewards(&mut self, badge_id: &NonFungibleLocalId, buyer: Option<NonFungibleGlobalId>, cost: Decimal) {
+        let (buyer_rate, seller_rate) = self.reward_rates;
+        if let Some(buyer) = buyer {
+            self.accrue_rewards(buyer, cost*buyer_rate);
+        }
+        let amount = self.capped_rewards(cost*seller_rate);
+        if amount == Decimal::zero() {
+            return;
+        }
+        match self.refund_accounts.get(badge_id).map(|account| account.address()) {
+            Some(address) => {
+                let unclaimed = self.account_rewards.get(&address).map_or(dec!(0), |reward| *reward);
+                self.account_rewards.insert(address, unclaimed + amount);
+            },
+            None => self.add_rewards(NonFungibleGlobalId::new(self.badge_address, badge_id.clone()), amount)
+        }
+    }
+    
+    // records any completed single NFT sale for the price feed and the history, takes the fee and credits the rest to the seller
+    fn settle_sale(&mut self, nft_id: &NonFungibleLocalId, badge_id: &NonFungibleLocalId, mut bucket: FungibleBucket, fee_rate: Decimal, buyer: Option<NonFungibleGlobalId>) {
+        let cost = bucket.amount();
+        self.set_state(badge_id, ListingState::Sold);
+        self.record_sale(cost);
+        let fee = cost*fee_rate;
+        self.record_history(nft_id, cost, fee, badge_id, buyer.clone());
+        Runtime::emit_event(SoldEvent {
+            badge_id: badge_id.clone(),
+            nft_id: nft_id.clone(),
+            price: cost,
+            fee_rate: fee_rate,
+            fee: fee,
+            buyer: buyer
+        });
+        let mut fee_bucket = bucket.take(fee);
+        let staker_fee = self.share_with_stakers(fee);
+        self.ccy_vault.put(fee_bucket.take(staker_fee));
+        self.fee_vault.put(fee_bucket);
+        self.fee_amount = self.fee_vault.amount();
+        self.credit(badge_id, bucket.amount());
+        self.ccy_vault.put(bucket);
+    }
+    
+    // the buyer claims the NFT with the bid receipt, not while the NFT is flagged
+    fn release_escrow(&mut self, receipt_id: &NonFungibleLocalId) {
+        let escrow = self.escrows.remove(receipt_id).unwrap();
+        self.ensure_not_flagged(&NonFungibleGlobalId::new(self.nft_address, escrow.nft_id.clone()));
+        self.escrow_terms.remove(&escrow.nft_id);
+        let bucket = self.ccy_vault.take(escrow.cost);
+        self.accrue_sale_rewards(&escrow.badge_id, escrow.buyer.clone(), escrow.cost);
+        self.settle_sale(&escrow.nft_id, &escrow.badge_id, bucket, escrow.fee_rate, escrow.buyer);
+        self.bid_claims.insert(receipt_id.clone(), (dec!(0), Some(escrow.nft_id)));
+    }
+    
+    fn escrow_receipt(&self, nft_id: &NonFungibleLocalId) -> NonFungibleLocalId {
+        self.escrow_terms.get(nft_id).and_then(|(_, receipt_id)| receipt_id.clone()).or_panic(MarketError::EscrowNotFound)
+    }
+    
+    // part of the fee accrued to the stakers, pro rata of their stake
+    fn share_with_stakers(&mut self, fee: Decimal) -> Decimal {
+        let staked = self.stake_vault.as_ref().map_or(dec!(0), |vault| vault.amount());
+        if staked == Decimal::zero() {
+            return dec!(0);
+        }
+        let staker_fee = fee*self.staker_share;
+        self.reward_per_share += staker_fee / staked;
+        staker_fee
+    }
+    
+    fn take_stake_rewards(&mut self, receipt_id: &NonFungibleLocalId) -> FungibleBucket {
+        let reward_per_share = self.reward_per_share;
+        let (amount, paid_per_share) = self.stakes.get_mut(receipt_id).or_panic(MarketError::InvalidReceipt);
+        let pending = *amount * (reward_per_share - *paid_per_share);
+        *paid_per_share = reward_per_share;
+        self.ccy_vault.take(pending)
+    }
+    
+    // accrued up to the emission cap
+    fn accrue_rewards(&mut self, account: NonFungibleGlobalId, amount: Decimal) {
+        let amount = self.capped_rewards(amount);
+        if amount > Decimal::zero() {
+            self.add_rewards(account, amount);
+        }
+    }
+    
+    // part of the amount left under the emission cap, counted as accrued
+    fn capped_rewards(&mut self, amount: Decimal) -> Decimal {
+        let amount = amount.min(self.reward_cap - self.rewards_accrued).max(dec!(0));
+        self.rewards_accrued += amount;
+        amount
+    }
+    
+    fn add_rewards(&mut self, account: NonFungibleGlobalId, amount: Decimal) {
+        let unclaimed = self.rewards.get(&account).map_or(dec!(0), |reward| *reward);
+        self.rewards.insert(account, unclaimed + amount);
+    }
+    
+    fn credit(&mut self, badge_id: &NonFungibleLocalId, amount: Decimal) {
+        *self.to_collect.entry(badge_id.clone()).or_insert(dec!(0)) += amount;
+    }
+    
+    // rewards of the seller badge or buyer NFT, here and on the previous components
+    fn take_rewards_of(&mut self, key: &NonFungibleGlobalId) -> Decimal {
+        let amount = self.rewards.remove(key).unwrap_or(dec!(0));
+        match self.previous {
+            Some(previous) => amount + Global::<NftSecondaryMarket>::from(previous).take_rewards(key.clone()),
+            None => amount
+        }
+    }
+    
+    fn listing(&self, badge_id: &NonFungibleLocalId) -> (NonFungibleLocalId, ListingState) {
+        if let Some(listing) = self.badges.get(badge_id) {
+            return listing.clone();
+        }
+        match self.previous {
+            Some(previous) => Global::<NftSecondaryMarket>::from(previous).badge_listing(badge_id.clone()),
+            None => panic!("{}", MarketError::InvalidBadge)
+        }
+    }
+    
+    fn set_state(&mut self, badge_id: &NonFungibleLocalId, state: ListingState) {
+        let (nft_id, _) = self.listing(badge_id);
+        self.badges.insert(badge_id.clone(), (nft_id, state));
+    }
+    
+    fn ensure_active(state: ListingState) {
+        match state {
+            ListingState::Active => (),
+            ListingState::Sold => panic!("{}", MarketError::ListingSold),
+            ListingState::Cancelled => panic!("{}", MarketError::ListingCancelled),
+            ListingState::Expired => panic!("{}", MarketError::ListingExpired)
+        }
+    }
+    
+    // CCY and NFT received by a sold listing
+    fn take_proceeds(&mut self, badge_id: &NonFungibleLocalId) -> (FungibleBucket, Option<NonFungibleBucket>) {
+        let amount = self.to_collect.remove(badge_id).or_panic(MarketError::NothingToCollect);
+        let nft_bucket = self.nft_to_collect.remove(badge_id).map(|item| self.take_item(&item));
+        (self.proceeds_vault(badge_id).take(amount), nft_bucket)
+    }
+    
+    fn close_rental(&mut self, badge_id: &NonFungibleLocalId, nft_id: &NonFungibleLocalId) -> (NonFungibleBucket, FungibleBucket) {
+        let (_, _, _, rented_until) = self.rentals.remove(nft_id).unwrap();
+        ensure(rented_until.map_or(true, |end| Self::is_past(end)), MarketError::RentalOngoing);
+        self.set_state(badge_id, ListingState::Cancelled);
+        let income = self.to_collect.remove(badge_id).unwrap();
+        (self.nft_vault.take_non_fungible(nft_id), self.ccy_vault.take(income))
+    }
+    
+    // the buyer must present in the auth zone a proof satisfying the market rule
+    fn check_buyer(&self) {
+        if let Some(rule) = &self.buyer_rule {
+            Runtime::assert_access_rule(rule.clone());
+        }
+    }
+  }
+}
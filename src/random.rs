@@ -0,0 +1,23 @@
+use scrypto::prelude::*;
+
+// source of the raffle draws, seeded by the caller
+pub trait RandomSource {
+  fn random(&self, seed: u64) -> u64;
+}
+
+// any component exposing `random(seed) -> u64`, ex: an oracle or the FixedRandom stand-in
+impl RandomSource for Global<AnyComponent> {
+  fn random(&self, seed: u64) -> u64 {
+    self.call_raw("random", scrypto_args!(seed))
+  }
+}
+
+// default source when no component is set, derived from the transaction: the caller can retry until it wins
+pub struct RuidSource;
+
+impl RandomSource for RuidSource {
+  fn random(&self, seed: u64) -> u64 {
+    let ruid = Runtime::generate_ruid();
+    u64::from_le_bytes(ruid[0..8].try_into().unwrap()) ^ seed
+  }
+}
@@ -1,342 +1,3161 @@
-use radix_engine::transaction::{TransactionReceipt, BalanceChange, CommitResult};
-use radix_engine::types::ManifestSbor;
-use scrypto::prelude::*;
-use scrypto_unit::*;
-use transaction::builder::ManifestBuilder;
-use transaction::signing::*;
-use transaction::model::{TransactionManifestV1};
-use transaction::signing::secp256k1::Secp256k1PrivateKey;
-
-type Actor = (Secp256k1PublicKey, Secp256k1PrivateKey, ComponentAddress);
-
-struct TestEnv {
-    runner: DefaultTestRunner,
-    instance: ComponentAddress,
-    nft_addr: ResourceAddress,
-    badge_addr: ResourceAddress
-}
-
-#[derive(ScryptoSbor, NonFungibleData, ManifestSbor)]
-struct EmptyNonFungibleData {}
-
-fn create_non_fungible_tokens<'a>(
-    runner: &mut DefaultTestRunner,
-    owner: &Actor,
-    ids: impl Iterator<Item = &'a u64>
-) -> ResourceAddress {
-    let mut entries = BTreeMap::new();
-    ids.for_each(|i| -> () { entries.insert(NonFungibleLocalId::integer(*i), EmptyNonFungibleData {}); });
-
-    let transaction = ManifestBuilder::new()
-        .create_non_fungible_resource(OwnerRole::None, NonFungibleIdType::Integer, false, NonFungibleResourceRoles::default(), metadata!(), Some(entries))
-        .deposit_batch(owner.2)
-        .build();
-    let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&owner.0)]);
-    receipt.expect_commit_success();
-    return receipt.expect_commit(true).new_resource_addresses()[0];
-}
-
-fn transfert_nft<'a>(
-    runner: &mut DefaultTestRunner,
-    addr: ResourceAddress,
-    ids: impl Iterator<Item = &'a u64>,
-    src: &Actor,
-    dest: &Actor) {
-    
-    let mut entries = BTreeSet::new();
-    ids.for_each(|i| -> () { entries.insert(NonFungibleLocalId::integer(*i)); });
-
-    let transaction = ManifestBuilder::new()
-        .withdraw_non_fungibles_from_account(src.2, addr, entries)
-        .deposit_batch(dest.2)
-        .build();
-    let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![
-      NonFungibleGlobalId::from_public_key(&src.0),
-      NonFungibleGlobalId::from_public_key(&dest.0)
-    ]);
-    receipt.expect_commit_success();
-}
-
-fn create_fungible_tokens(
-    runner: &mut DefaultTestRunner,
-    owner: &Actor,
-    nb: Decimal
-) -> ResourceAddress {
-    let transaction = ManifestBuilder::new()
-        .new_token_fixed(OwnerRole::None, metadata!(), nb)
-        .deposit_batch(owner.2)
-        .build();
-    let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&owner.0)]);
-    receipt.expect_commit_success();
-    return receipt.expect_commit(true).new_resource_addresses()[0];
-}
-
-impl TestEnv {
-    fn new(fee_rate: Decimal) -> (
-        TestEnv,
-        Actor,      // seller: key, account
-        Vec<Actor>, // buyers: key, account
-        ResourceAddress, // NFT address
-        ResourceAddress  // Fee owner Badge
-    ) {
-        let mut runner = TestRunnerBuilder::new().without_trace().build();
-        let seller = runner.new_allocated_account();
-        let nft_addr = create_non_fungible_tokens(&mut runner, &seller, [1,2,3].iter());
-        let buyers: Vec<Actor> = (0..3).map(|_| runner.new_allocated_account()).collect();
-        let package = runner.compile_and_publish(this_package!());
-        
-        let fee_badge = create_fungible_tokens(&mut runner, &seller, dec!(1));
-        
-        let transaction = ManifestBuilder::new()
-            .call_function(package, "NftSecondaryMarket", "instantiate_component", manifest_args!(nft_addr, XRD, fee_badge, fee_rate))
-            .deposit_batch(seller.2)
-            .build();
-        let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&seller.0)]);
-        println!("{:?}\n", receipt);
-        receipt.expect_commit_success();
-        let result = &receipt.expect_commit(true);
-        //println!("{:?}\n", result);
-        let instance = result.new_component_addresses()[0];
-        let badge_addr = result.new_resource_addresses()[0];
-        (
-            TestEnv {
-                runner,
-                instance,
-                nft_addr,
-                badge_addr,
-            },
-            seller,
-            buyers,
-            nft_addr,
-            fee_badge
-        )
-    }
-    
-    fn execute(&mut self, transaction: TransactionManifestV1, actor: &Actor) -> TransactionReceipt {
-      self.runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&actor.0)])
-    }
-    
-    fn sell(&mut self, actor: &Actor, id: &NonFungibleLocalId, cost: Decimal) -> NonFungibleLocalId {
-        let transaction = ManifestBuilder::new()
-            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
-            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
-            .call_method_with_name_lookup(self.instance, "sell", |lookup| (
-                  lookup.bucket("nft"),
-                        cost
-                )
-              )
-            .deposit_batch(actor.2)
-            .build();
-        let receipt = self.execute(transaction, actor);
-        println!("{:?}\n", receipt);
-        let result = receipt.expect_commit_success();
-        //scrypto_decode(&result[2].as_vec()).unwrap()
-        //IndexMap<GlobalAddress, IndexMap<ResourceAddress, BalanceChange>>
-        println!("vault_balance_changes: {:?}\n", result.vault_balance_changes());
-        let actor_account = actor.2.as_node_id();
-        let changes = self.runner.sum_descendant_balance_changes(result, actor_account);
-        let (badge_addr, val) = changes.iter().filter(|(k, _)| **k == self.badge_addr).next().unwrap();
-        println!("id: {:?} {:?}\n", id, val);
-        val.clone().added_non_fungibles().iter().next().unwrap().clone()
-    }
-    
-    fn collect(&mut self, actor: &Actor, badge: &NonFungibleLocalId) -> CommitResult {
-        let transaction = ManifestBuilder::new()
-            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
-            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
-            .call_method_with_name_lookup(self.instance, "collect", |lookup| (
-                  lookup.bucket("badge"),
-                )
-              )
-            .deposit_batch(actor.2)
-            .build();
-        let receipt = self.execute(transaction, actor);
-        println!("{:?}\n", receipt);
-        receipt.expect_commit_success().clone()
-    }
-    
-    fn buy_intern(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal, should_fail: bool) {
-        let transaction = ManifestBuilder::new()
-            .withdraw_from_account(actor.2, XRD, amount)
-            .take_all_from_worktop(XRD, "ccy")
-            .call_method_with_name_lookup(self.instance, "buy", |lookup| (
-                  id.clone(),
-                  lookup.bucket("ccy")
-                )
-              )
-            .deposit_batch(actor.2)
-            .build();
-        let receipt = self.execute(transaction, actor);
-        println!("{:?}\n", receipt);
-        if should_fail {
-          receipt.expect_commit_failure();
-        } else {
-          receipt.expect_commit_success();
-        }
-    }
-    
-    fn buy(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal) {
-        self.buy_intern(actor, id, amount, false);
-    }
-    
-    fn buy_fail(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal) {
-        self.buy_intern(actor, id, amount, true);
-    }
-    
-    fn cancel(&mut self, actor: &Actor, badge: &NonFungibleLocalId) {
-        let transaction = ManifestBuilder::new()
-            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
-            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
-            .call_method_with_name_lookup(self.instance, "cancel", |lookup| (
-                  lookup.bucket("badge"),
-                )
-              )
-            .deposit_batch(actor.2)
-            .build();
-        let receipt = self.execute(transaction, actor);
-        println!("{:?}\n", receipt);
-        receipt.expect_commit_success();
-    }
-    
-    fn update(&mut self, actor: &Actor, badge: &NonFungibleLocalId, cost: Decimal) {
-        let transaction = ManifestBuilder::new()
-            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
-            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
-            .call_method_with_name_lookup(self.instance, "update", |lookup| (
-                  lookup.bucket("badge"),
-                  cost
-                )
-              )
-            .deposit_batch(actor.2)
-            .build();
-        let receipt = self.execute(transaction, actor);
-        println!("{:?}\n", receipt);
-        receipt.expect_commit_success();
-    }
-    
-    fn collect_fees(&mut self, actor: &Actor, fee_badge: ResourceAddress) -> CommitResult {
-        let transaction = ManifestBuilder::new()
-            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
-            .call_method(self.instance,"collect_fees", manifest_args!())
-            .deposit_batch(actor.2)
-            .build();
-        let receipt = self.execute(transaction, actor);
-        receipt.expect_commit_success().clone()
-    }
-    
-    fn check_balance_change(&mut self, commit_result: &CommitResult, actor: &Actor, ressource: ResourceAddress, exp_amount: Decimal) {
-        let balance_changes = commit_result.vault_balance_changes();
-        for (vault_id, (resource, delta)) in balance_changes.iter() {
-            println!("Vault: {:?}\n   Resource: {:?}\n   Change: {}",
-                vault_id,
-                resource,
-                match delta {
-                    BalanceChange::Fungible(d) => format!("{}", d),
-                    BalanceChange::NonFungible { added, removed } => { format!("+{:?}, -{:?}", added, removed) }
-                }
-            );
-        }
-        let balances = self.runner.sum_descendant_balance_changes(commit_result, actor.2.as_node_id());
-        let zeroBalance = BalanceChange::Fungible(dec!(0));
-        let balance = balances.get(&ressource).unwrap_or(&zeroBalance);
-        let amount = match balance {
-                        BalanceChange::Fungible(d) => d,
-                        _ => panic!("expect fungible")
-                     };
-        println!("{:?}\n", self.runner.sum_descendant_balance_changes(commit_result, actor.2.as_node_id()));
-        assert_eq!(*amount, exp_amount);
-    }
-}
-
-#[test]
-fn test_instantiate() {
-    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
-}
-
-#[test]
-fn test_sell() {
-    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
-    let id = NonFungibleLocalId::integer(1);
-    env.sell(&owner, &id, dec!(5));
-}
-
-#[test]
-fn test_sell_buy() {
-    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
-    let id = NonFungibleLocalId::integer(1);
-    let badge = env.sell(&owner, &id, dec!(5));
-    env.buy(&buyers[0], &id, dec!(5));
-}
-
-#[test]
-fn test_sell_buy_collect() {
-    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
-    let id = NonFungibleLocalId::integer(1);
-    let badge = env.sell(&owner, &id, dec!(5));
-    env.buy(&buyers[0], &id, dec!(5));
-    let result = env.collect(&owner, &badge);
-    env.check_balance_change(&result, &owner, XRD, dec!(5));
-}
-
-#[test]
-fn test_sell_cancel() {
-    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
-    let id = NonFungibleLocalId::integer(1);
-    let badge = env.sell(&owner, &id, dec!(5));
-    env.cancel(&owner, &badge);
-}
-
-#[test]
-fn test_sell_cancel_buy_fail() {
-    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
-    let id = NonFungibleLocalId::integer(1);
-    let badge = env.sell(&owner, &id, dec!(5));
-    env.cancel(&owner, &badge);
-    env.buy_fail(&buyers[0], &id, dec!(5));
-}
-
-#[test]
-fn test_sell_update_buy_collect() {
-    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
-    let id = NonFungibleLocalId::integer(1);
-    let badge = env.sell(&owner, &id, dec!(5));
-    env.update(&owner, &badge, dec!(4));
-    env.buy(&buyers[0], &id, dec!(4));
-}
-
-#[test]
-fn test_sell_buy_low_fail() {
-    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
-    let id = NonFungibleLocalId::integer(1);
-    let badge = env.sell(&owner, &id, dec!(5));
-    env.update(&owner, &badge, dec!(6));
-    env.buy_fail(&buyers[0], &id, dec!(5));
-}
-
-#[test]
-fn test_sell_buy_sell_collect() {
-    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
-    let id = NonFungibleLocalId::integer(1);
-    let badge = env.sell(&owner, &id, dec!(5));
-    env.buy(&buyers[0], &id, dec!(5));
-    let badge2 = env.sell(&buyers[0], &id, dec!(10));
-    let result = env.collect(&owner, &badge);
-    env.check_balance_change(&result, &owner, XRD, dec!(5));
-}
-
-#[test]
-fn test_fee() {
-    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0.25));
-    let id = NonFungibleLocalId::integer(1);
-    let badge = env.sell(&owner, &id, dec!(20));
-    env.buy(&buyers[0], &id, dec!(20));
-    let badge2 = env.sell(&buyers[0], &id, dec!(40));
-    env.buy(&buyers[1], &id, dec!(40));
-    let result_collect = env.collect(&owner, &badge);
-    env.check_balance_change(&result_collect, &owner, XRD, dec!(15));
-    let result_collect2 = env.collect(&buyers[0], &badge2);
-    env.check_balance_change(&result_collect2, &buyers[0], XRD, dec!(30));
-    let result_fee = env.collect_fees(&owner, fee_badge);
-    env.check_balance_change(&result_fee, &owner, XRD, dec!(15));
-}
-
+use radix_engine::errors::{ApplicationError, RuntimeError};
+use radix_engine::transaction::{TransactionReceipt, BalanceChange, CommitResult};
+use radix_engine::types::{ManifestProof, ManifestSbor};
+use scrypto::prelude::*;
+use scrypto_unit::*;
+use transaction::builder::ManifestBuilder;
+use transaction::signing::*;
+use transaction::model::{TransactionManifestV1};
+use transaction::signing::secp256k1::Secp256k1PrivateKey;
+
+type Actor = (Secp256k1PublicKey, Secp256k1PrivateKey, ComponentAddress);
+
+struct TestEnv {
+    runner: DefaultTestRunner,
+    package: PackageAddress,
+    instance: ComponentAddress,
+    nft_addr: ResourceAddress,
+    badge_addr: ResourceAddress,
+    rental_addr: ResourceAddress,
+    loan_addr: ResourceAddress,
+    bid_addr: ResourceAddress,
+    pool_addr: ResourceAddress,
+    reward_addr: ResourceAddress,
+    stake_addr: ResourceAddress,
+    round: u64,
+    now_ms: i64
+}
+
+#[derive(ScryptoSbor, NonFungibleData, ManifestSbor)]
+struct EmptyNonFungibleData {}
+
+#[derive(ScryptoSbor, NonFungibleData, ManifestSbor)]
+struct TraitData {
+    background: String,
+    level: u64
+}
+
+// rental receipt of a trait token, read back from the ledger
+#[derive(ScryptoSbor, NonFungibleData)]
+struct TraitRentalReceipt {
+    name: String,
+    description: String,
+    nft_address: ResourceAddress,
+    nft_id: NonFungibleLocalId,
+    nft_data: TraitData,
+    expires_at: Instant,
+    component_address: ComponentAddress
+}
+
+#[derive(ManifestSbor)]
+struct TraitConstraint {
+    field: String,
+    value: String
+}
+
+#[derive(ManifestSbor, Clone)]
+struct AuctionTerms {
+    start_price: Decimal,
+    reserve_price: Decimal,
+    public_reserve: bool,
+    buy_now_price: Option<Decimal>,
+    end_time: Instant,
+    extension_minutes: i64
+}
+
+#[derive(ManifestSbor)]
+struct LayawayTerms {
+    deposit: Decimal,
+    installments: u64,
+    interval_days: i64,
+    forfeit_rate: Decimal
+}
+
+#[derive(ManifestSbor)]
+enum ListingTerms {
+    FixedPrice(Decimal),
+    Auction(AuctionTerms),
+    Swap(SwapWant, Decimal)
+}
+
+#[derive(ScryptoSbor, Debug, PartialEq)]
+enum ListingState {
+    Active,
+    Sold,
+    Cancelled,
+    Expired
+}
+
+#[derive(ScryptoSbor, Debug)]
+struct SaleRecord {
+    price: Decimal,
+    fee: Decimal,
+    time: Instant,
+    seller_badge: NonFungibleLocalId,
+    buyer: Option<NonFungibleGlobalId>
+}
+
+#[derive(ManifestSbor)]
+enum Curve {
+    Linear(Decimal),
+    Exponential(Decimal)
+}
+
+#[derive(ManifestSbor)]
+enum SwapWant {
+    Ids(Vec<NonFungibleGlobalId>),
+    Resource(ResourceAddress)
+}
+
+fn create_non_fungible_tokens<'a>(
+    runner: &mut DefaultTestRunner,
+    owner: &Actor,
+    ids: impl Iterator<Item = &'a u64>
+) -> ResourceAddress {
+    let mut entries = BTreeMap::new();
+    ids.for_each(|i| -> () { entries.insert(NonFungibleLocalId::integer(*i), EmptyNonFungibleData {}); });
+
+    let transaction = ManifestBuilder::new()
+        .create_non_fungible_resource(OwnerRole::None, NonFungibleIdType::Integer, false, NonFungibleResourceRoles::default(), metadata!(), Some(entries))
+        .deposit_batch(owner.2)
+        .build();
+    let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&owner.0)]);
+    receipt.expect_commit_success();
+    return receipt.expect_commit(true).new_resource_addresses()[0];
+}
+
+fn create_trait_tokens(
+    runner: &mut DefaultTestRunner,
+    owner: &Actor,
+    traits: Vec<(u64, &str, u64)>
+) -> ResourceAddress {
+    let mut entries = BTreeMap::new();
+    traits.into_iter().for_each(|(i, background, level)| -> () {
+        entries.insert(NonFungibleLocalId::integer(i), TraitData { background: background.to_string(), level });
+    });
+
+    let transaction = ManifestBuilder::new()
+        .create_non_fungible_resource(OwnerRole::None, NonFungibleIdType::Integer, false, NonFungibleResourceRoles::default(), metadata!(), Some(entries))
+        .deposit_batch(owner.2)
+        .build();
+    let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&owner.0)]);
+    receipt.expect_commit_success();
+    return receipt.expect_commit(true).new_resource_addresses()[0];
+}
+
+fn transfert_nft<'a>(
+    runner: &mut DefaultTestRunner,
+    addr: ResourceAddress,
+    ids: impl Iterator<Item = &'a u64>,
+    src: &Actor,
+    dest: &Actor) {
+    
+    let mut entries = BTreeSet::new();
+    ids.for_each(|i| -> () { entries.insert(NonFungibleLocalId::integer(*i)); });
+
+    let transaction = ManifestBuilder::new()
+        .withdraw_non_fungibles_from_account(src.2, addr, entries)
+        .deposit_batch(dest.2)
+        .build();
+    let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![
+      NonFungibleGlobalId::from_public_key(&src.0),
+      NonFungibleGlobalId::from_public_key(&dest.0)
+    ]);
+    receipt.expect_commit_success();
+}
+
+fn transfert_tokens(
+    runner: &mut DefaultTestRunner,
+    addr: ResourceAddress,
+    amount: Decimal,
+    src: &Actor,
+    dest: &Actor) {
+    
+    let transaction = ManifestBuilder::new()
+        .withdraw_from_account(src.2, addr, amount)
+        .deposit_batch(dest.2)
+        .build();
+    let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![
+      NonFungibleGlobalId::from_public_key(&src.0),
+      NonFungibleGlobalId::from_public_key(&dest.0)
+    ]);
+    receipt.expect_commit_success();
+}
+
+fn create_fungible_tokens(
+    runner: &mut DefaultTestRunner,
+    owner: &Actor,
+    nb: Decimal
+) -> ResourceAddress {
+    let transaction = ManifestBuilder::new()
+        .new_token_fixed(OwnerRole::None, metadata!(), nb)
+        .deposit_batch(owner.2)
+        .build();
+    let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&owner.0)]);
+    receipt.expect_commit_success();
+    return receipt.expect_commit(true).new_resource_addresses()[0];
+}
+
+impl TestEnv {
+    fn new(fee_rate: Decimal) -> (
+        TestEnv,
+        Actor,      // seller: key, account
+        Vec<Actor>, // buyers: key, account
+        ResourceAddress, // NFT address
+        ResourceAddress  // Fee owner Badge
+    ) {
+        let (env, seller, buyers, nft_addr, fee_badge, _) = TestEnv::new_with_kyc(fee_rate, false);
+        (env, seller, buyers, nft_addr, fee_badge)
+    }
+    
+    // the KYC badge is given to the first buyer only
+    fn new_with_kyc(fee_rate: Decimal, kyc_required: bool) -> (
+        TestEnv,
+        Actor,      // seller: key, account
+        Vec<Actor>, // buyers: key, account
+        ResourceAddress, // NFT address
+        ResourceAddress, // Fee owner Badge
+        ResourceAddress  // KYC Badge
+    ) {
+        TestEnv::new_with(fee_rate, kyc_required, |runner, seller| create_non_fungible_tokens(runner, seller, [1,2,3].iter()))
+    }
+    
+    // ids 1 and 2 have a gold background, id 3 a blue one
+    fn new_with_traits(fee_rate: Decimal) -> (
+        TestEnv,
+        Actor,      // seller: key, account
+        Vec<Actor>, // buyers: key, account
+        ResourceAddress, // NFT address
+        ResourceAddress  // Fee owner Badge
+    ) {
+        let (env, seller, buyers, nft_addr, fee_badge, _) = TestEnv::new_with(fee_rate, false, |runner, seller|
+            create_trait_tokens(runner, seller, vec![(1, "gold", 1), (2, "gold", 5), (3, "blue", 5)])
+        );
+        (env, seller, buyers, nft_addr, fee_badge)
+    }
+    
+    fn new_with(fee_rate: Decimal, kyc_required: bool, create_nfts: impl FnOnce(&mut DefaultTestRunner, &Actor) -> ResourceAddress) -> (
+        TestEnv,
+        Actor,      // seller: key, account
+        Vec<Actor>, // buyers: key, account
+        ResourceAddress, // NFT address
+        ResourceAddress, // Fee owner Badge
+        ResourceAddress  // KYC Badge
+    ) {
+        let mut runner = TestRunnerBuilder::new().without_trace().build();
+        let seller = runner.new_allocated_account();
+        let nft_addr = create_nfts(&mut runner, &seller);
+        let buyers: Vec<Actor> = (0..3).map(|_| runner.new_allocated_account()).collect();
+        let package = runner.compile_and_publish(this_package!());
+        
+        let fee_badge = create_fungible_tokens(&mut runner, &seller, dec!(1));
+        let kyc_badge = create_fungible_tokens(&mut runner, &buyers[0], dec!(1));
+        let buyer_rule = if kyc_required { Some(rule!(require(kyc_badge))) } else { None };
+        
+        let transaction = ManifestBuilder::new()
+            .call_function(package, "NftSecondaryMarket", "instantiate_component", manifest_args!(nft_addr, XRD, fee_badge, fee_rate, buyer_rule))
+            .deposit_batch(seller.2)
+            .build();
+        let receipt = runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&seller.0)]);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+        let result = &receipt.expect_commit(true);
+        //println!("{:?}\n", result);
+        let instance = result.new_component_addresses()[0];
+        // [0] is the authority kept by the component
+        let badge_addr = result.new_resource_addresses()[1];
+        let rental_addr = result.new_resource_addresses()[2];
+        let loan_addr = result.new_resource_addresses()[3];
+        let bid_addr = result.new_resource_addresses()[4];
+        let pool_addr = result.new_resource_addresses()[5];
+        let reward_addr = result.new_resource_addresses()[6];
+        let stake_addr = result.new_resource_addresses()[7];
+        (
+            TestEnv {
+                runner,
+                package,
+                instance,
+                nft_addr,
+                badge_addr,
+                rental_addr,
+                loan_addr,
+                bid_addr,
+                pool_addr,
+                reward_addr,
+                stake_addr,
+                round: 1,
+                now_ms: 1_699_999_980_000, // on a minute boundary, the clock is read at minute precision
+            },
+            seller,
+            buyers,
+            nft_addr,
+            fee_badge,
+            kyc_badge
+        )
+    }
+    
+    fn execute(&mut self, transaction: TransactionManifestV1, actor: &Actor) -> TransactionReceipt {
+      self.runner.execute_manifest_ignoring_fee(transaction, vec![NonFungibleGlobalId::from_public_key(&actor.0)])
+    }
+    
+    fn sell(&mut self, actor: &Actor, id: &NonFungibleLocalId, cost: Decimal) -> NonFungibleLocalId {
+        self.sell_scheduled(actor, id, cost, None)
+    }
+    
+    fn sell_scheduled(&mut self, actor: &Actor, id: &NonFungibleLocalId, cost: Decimal, starts_at: Option<Instant>) -> NonFungibleLocalId {
+        self.sell_full(actor, id, cost, starts_at, None)
+    }
+    
+    // the seller account receives the NFT or the proceeds if the market winds down
+    fn sell_registered(&mut self, actor: &Actor, id: &NonFungibleLocalId, cost: Decimal) -> NonFungibleLocalId {
+        self.sell_full(actor, id, cost, None, Some(actor.2))
+    }
+    
+    fn sell_full(&mut self, actor: &Actor, id: &NonFungibleLocalId, cost: Decimal, starts_at: Option<Instant>, refund_account: Option<ComponentAddress>) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "sell", |lookup| (
+                  lookup.bucket("nft"),
+                        cost,
+                        starts_at,
+                        refund_account
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        //scrypto_decode(&result[2].as_vec()).unwrap()
+        //IndexMap<GlobalAddress, IndexMap<ResourceAddress, BalanceChange>>
+        println!("vault_balance_changes: {:?}\n", result.vault_balance_changes());
+        let actor_account = actor.2.as_node_id();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor_account);
+        let (badge_addr, val) = changes.iter().filter(|(k, _)| **k == self.badge_addr).next().unwrap();
+        println!("id: {:?} {:?}\n", id, val);
+        val.clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn collect(&mut self, actor: &Actor, badge: &NonFungibleLocalId) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
+            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
+            .call_method_with_name_lookup(self.instance, "collect", |lookup| (
+                  lookup.bucket("badge"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn buy_intern(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal, should_fail: bool) {
+        self.buy_with_proof(actor, id, amount, None, should_fail);
+    }
+    
+    fn buy_with_proof(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal, proof: Option<ResourceAddress>, should_fail: bool) -> TransactionReceipt {
+        self.buy_full(actor, id, amount, proof, None, None, should_fail)
+    }
+    
+    // the buyer identifies with one of its NFTs to accrue rewards
+    fn buy_as(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal, buyer: &NonFungibleGlobalId) {
+        self.buy_full(actor, id, amount, None, Some(buyer), None, false);
+    }
+    
+    fn buy_full(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal, proof: Option<ResourceAddress>, buyer: Option<&NonFungibleGlobalId>, discount: Option<(ResourceAddress, Decimal)>, should_fail: bool) -> TransactionReceipt {
+        let mut builder = ManifestBuilder::new();
+        if let Some(proof) = proof {
+            builder = builder.create_proof_from_account_of_amount(actor.2, proof, dec!(1));
+        }
+        if let Some(buyer) = buyer {
+            builder = builder
+                .create_proof_from_account_of_non_fungibles(actor.2, buyer.resource_address(), BTreeSet::from([buyer.local_id().clone()]))
+                .pop_from_auth_zone("buyer");
+        }
+        if let Some((resource, amount)) = discount {
+            builder = builder
+                .create_proof_from_account_of_amount(actor.2, resource, amount)
+                .pop_from_auth_zone("discount");
+        }
+        let transaction = builder
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "buy", |lookup| (
+                  id.clone(),
+                  lookup.bucket("ccy"),
+                  buyer.map(|_| lookup.proof("buyer")),
+                  discount.map(|_| lookup.proof("discount"))
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    // the buyer proves holding a loyalty resource for a discounted fee
+    fn buy_discounted(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal, discount: (ResourceAddress, Decimal)) -> TransactionReceipt {
+        self.buy_full(actor, id, amount, None, None, Some(discount), false)
+    }
+    
+    fn buy(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal) {
+        self.buy_intern(actor, id, amount, false);
+    }
+    
+    fn buy_fail(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal) {
+        self.buy_intern(actor, id, amount, true);
+    }
+    
+    fn cancel(&mut self, actor: &Actor, badge: &NonFungibleLocalId) {
+        self.cancel_intern(actor, badge, false);
+    }
+    
+    fn cancel_fail(&mut self, actor: &Actor, badge: &NonFungibleLocalId) {
+        self.cancel_intern(actor, badge, true);
+    }
+    
+    fn cancel_intern(&mut self, actor: &Actor, badge: &NonFungibleLocalId, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
+            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
+            .call_method_with_name_lookup(self.instance, "cancel", |lookup| (
+                  lookup.bucket("badge"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn update(&mut self, actor: &Actor, badge: &NonFungibleLocalId, cost: Decimal) {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
+            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
+            .call_method_with_name_lookup(self.instance, "update", |lookup| (
+                  lookup.bucket("badge"),
+                  cost
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn collect_fees(&mut self, actor: &Actor, fee_badge: ResourceAddress) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance,"collect_fees", manifest_args!())
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn collect_currency_fees(&mut self, actor: &Actor, fee_badge: ResourceAddress, currency: ResourceAddress) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance,"collect_currency_fees", manifest_args!(currency))
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn sell_swap(&mut self, actor: &Actor, id: &NonFungibleLocalId, wanted: SwapWant, sweetener: Decimal) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "sell_swap", |lookup| (
+                  lookup.bucket("nft"),
+                  wanted,
+                  sweetener
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.badge_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn fill_swap(&mut self, actor: &Actor, id: &NonFungibleLocalId, item: &NonFungibleGlobalId, amount: Decimal, should_fail: bool) {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, item.resource_address(), BTreeSet::from([item.local_id().clone()]))
+            .take_non_fungibles_from_worktop(item.resource_address(), BTreeSet::from([item.local_id().clone()]), "item")
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "fill_swap", |lookup| (
+                  id.clone(),
+                  lookup.bucket("item"),
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+    }
+    
+    // items are taken from the actor account, grouped by collection
+    fn sell_bundle(&mut self, actor: &Actor, items: &Vec<NonFungibleGlobalId>, cost: Decimal) -> NonFungibleLocalId {
+        let mut collections: BTreeMap<ResourceAddress, BTreeSet<NonFungibleLocalId>> = BTreeMap::new();
+        items.iter().for_each(|item| { collections.entry(item.resource_address()).or_default().insert(item.local_id().clone()); });
+        let mut builder = ManifestBuilder::new();
+        let mut names = Vec::new();
+        for (i, (address, ids)) in collections.into_iter().enumerate() {
+            let name = format!("nft{}", i);
+            builder = builder
+                .withdraw_non_fungibles_from_account(actor.2, address, ids.clone())
+                .take_non_fungibles_from_worktop(address, ids, name.clone());
+            names.push(name);
+        }
+        let transaction = builder
+            .call_method_with_name_lookup(self.instance, "sell_bundle", |lookup| (
+                  names.iter().map(|name| lookup.bucket(name.clone())).collect::<Vec<ManifestBucket>>(),
+                  cost
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.badge_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn buy_bundle(&mut self, actor: &Actor, bundle_id: &NonFungibleLocalId, amount: Decimal, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "buy_bundle", |lookup| (
+                  bundle_id.clone(),
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn allow_bundle_collection(&mut self, actor: &Actor, fee_badge: ResourceAddress, address: ResourceAddress) {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "allow_bundle_collection", manifest_args!(address))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn advance_time(&mut self, seconds: i64) {
+        self.round += 1;
+        self.now_ms += seconds * 1000;
+        self.runner.advance_to_round_at_timestamp(Round::of(self.round), self.now_ms);
+    }
+    
+    fn list_rental(&mut self, actor: &Actor, id: &NonFungibleLocalId, price_per_day: Decimal, max_days: u64) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "list_rental", |lookup| (
+                  lookup.bucket("nft"),
+                  price_per_day,
+                  max_days
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.badge_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn rent(&mut self, actor: &Actor, id: &NonFungibleLocalId, days: u64, amount: Decimal, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "rent", |lookup| (
+                  id.clone(),
+                  days,
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn reclaim_rental(&mut self, actor: &Actor, badge: &NonFungibleLocalId, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
+            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
+            .call_method_with_name_lookup(self.instance, "reclaim_rental", |lookup| (
+                  lookup.bucket("badge"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn offer_loan(&mut self, actor: &Actor, amount: Decimal, interest: Decimal, duration_days: u64) -> NonFungibleLocalId {
+        let receipt = self.offer_loan_intern(actor, amount, interest, duration_days, false);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.loan_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn offer_loan_intern(&mut self, actor: &Actor, amount: Decimal, interest: Decimal, duration_days: u64, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "offer_loan", |lookup| (
+                  lookup.bucket("ccy"),
+                  interest,
+                  duration_days
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn borrow(&mut self, actor: &Actor, offer: &NonFungibleLocalId, id: &NonFungibleLocalId) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "borrow", |lookup| (
+                  offer.clone(),
+                  lookup.bucket("nft")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.loan_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn repay(&mut self, actor: &Actor, badge: &NonFungibleLocalId, amount: Decimal, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.loan_addr, BTreeSet::from([badge.clone()]))
+            .take_non_fungibles_from_worktop(self.loan_addr, BTreeSet::from([badge.clone()]), "badge")
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "repay", |lookup| (
+                  lookup.bucket("badge"),
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn collect_loan(&mut self, actor: &Actor, badge: &NonFungibleLocalId, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.loan_addr, BTreeSet::from([badge.clone()]))
+            .take_non_fungibles_from_worktop(self.loan_addr, BTreeSet::from([badge.clone()]), "badge")
+            .call_method_with_name_lookup(self.instance, "collect_loan", |lookup| (
+                  lookup.bucket("badge"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn instant_in(&self, seconds: i64) -> Instant {
+        Instant::new(self.now_ms / 1000 + seconds)
+    }
+    
+    fn sell_sealed(&mut self, actor: &Actor, id: &NonFungibleLocalId, min_price: Decimal, bid_end: Instant, reveal_end: Instant, second_price: bool) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "sell_sealed", |lookup| (
+                  lookup.bucket("nft"),
+                  min_price,
+                  bid_end,
+                  reveal_end,
+                  second_price
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.badge_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn sealed_bid(&mut self, actor: &Actor, id: &NonFungibleLocalId, price: Decimal, salt: &str, deposit: Decimal) -> NonFungibleLocalId {
+        let commitment = hash(scrypto_encode(&(price, salt.to_string())).unwrap());
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, deposit)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "sealed_bid", |lookup| (
+                  id.clone(),
+                  commitment,
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.bid_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn reveal_bid(&mut self, actor: &Actor, bid: &NonFungibleLocalId, price: Decimal, salt: &str, should_fail: bool) {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.bid_addr, BTreeSet::from([bid.clone()]))
+            .take_non_fungibles_from_worktop(self.bid_addr, BTreeSet::from([bid.clone()]), "bid")
+            .call_method_with_name_lookup(self.instance, "reveal_bid", |lookup| (
+                  lookup.bucket("bid"),
+                  price,
+                  salt.to_string()
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+    }
+    
+    fn settle_sealed(&mut self, actor: &Actor, id: &NonFungibleLocalId) {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "settle_sealed", manifest_args!(id.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn claim_bid(&mut self, actor: &Actor, bid: &NonFungibleLocalId) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.bid_addr, BTreeSet::from([bid.clone()]))
+            .take_non_fungibles_from_worktop(self.bid_addr, BTreeSet::from([bid.clone()]), "bid")
+            .call_method_with_name_lookup(self.instance, "claim_bid", |lookup| (
+                  lookup.bucket("bid"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn offer_layaway(&mut self, actor: &Actor, badge: &NonFungibleLocalId, terms: LayawayTerms) {
+        self.offer_layaway_intern(actor, badge, terms, false);
+    }
+    
+    fn offer_layaway_intern(&mut self, actor: &Actor, badge: &NonFungibleLocalId, terms: LayawayTerms, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
+            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
+            .call_method_with_name_lookup(self.instance, "offer_layaway", |lookup| (
+                  lookup.bucket("badge"),
+                  terms
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn start_layaway(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "start_layaway", |lookup| (
+                  id.clone(),
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.bid_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn pay_installment(&mut self, actor: &Actor, receipt_id: &NonFungibleLocalId, amount: Decimal, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "pay_installment", |lookup| (
+                  receipt_id.clone(),
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn default_layaway(&mut self, actor: &Actor, receipt_id: &NonFungibleLocalId) {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "default_layaway", manifest_args!(receipt_id.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn sell_escrowed(&mut self, actor: &Actor, id: &NonFungibleLocalId, cost: Decimal, confirm_days: i64) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "sell_escrowed", |lookup| (
+                  lookup.bucket("nft"),
+                  cost,
+                  confirm_days
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.badge_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    // an escrowed buy returns a bid receipt instead of the NFT
+    fn buy_escrowed(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal) -> NonFungibleLocalId {
+        let receipt = self.buy_full(actor, id, amount, None, None, None, false);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.bid_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn confirm_escrow(&mut self, actor: &Actor, receipt_id: &NonFungibleLocalId) -> CommitResult {
+        let receipt = self.confirm_escrow_intern(actor, receipt_id, false);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn confirm_escrow_intern(&mut self, actor: &Actor, receipt_id: &NonFungibleLocalId, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.bid_addr, BTreeSet::from([receipt_id.clone()]))
+            .take_non_fungibles_from_worktop(self.bid_addr, BTreeSet::from([receipt_id.clone()]), "receipt")
+            .call_method_with_name_lookup(self.instance, "confirm_escrow", |lookup| (
+                  lookup.bucket("receipt"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn timeout_escrow(&mut self, actor: &Actor, id: &NonFungibleLocalId) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "timeout_escrow", manifest_args!(id.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt
+    }
+    
+    // the seller proves with the badge, the buyer with the bid receipt
+    fn dispute_escrow(&mut self, actor: &Actor, id: &NonFungibleLocalId, party: &NonFungibleGlobalId) {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_non_fungibles(actor.2, party.resource_address(), BTreeSet::from([party.local_id().clone()]))
+            .pop_from_auth_zone("proof")
+            .call_method_with_name_lookup(self.instance, "dispute_escrow", |lookup| (
+                  id.clone(),
+                  lookup.proof("proof")
+                )
+              )
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn resolve_escrow(&mut self, actor: &Actor, arbiter_badge: ResourceAddress, id: &NonFungibleLocalId, release: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, arbiter_badge, dec!(1))
+            .call_method(self.instance, "resolve_escrow", manifest_args!(id.clone(), release))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt
+    }
+    
+    fn sell_auction(&mut self, actor: &Actor, id: &NonFungibleLocalId, terms: AuctionTerms) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "sell_auction", |lookup| (
+                  lookup.bucket("nft"),
+                  terms
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.badge_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn bid_intern(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal, should_fail: bool) -> Option<NonFungibleLocalId> {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "bid", |lookup| (
+                  id.clone(),
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+          return None;
+        }
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        Some(changes.get(&self.bid_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone())
+    }
+    
+    fn bid(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal) -> NonFungibleLocalId {
+        self.bid_intern(actor, id, amount, false).unwrap()
+    }
+    
+    fn bid_fail(&mut self, actor: &Actor, id: &NonFungibleLocalId, amount: Decimal) {
+        self.bid_intern(actor, id, amount, true);
+    }
+    
+    fn settle_auction(&mut self, actor: &Actor, id: &NonFungibleLocalId) {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "settle_auction", manifest_args!(id.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn create_pool(&mut self, actor: &Actor, ids: &[u64], amount: Decimal, spot_price: Decimal, curve: Curve, spread: Decimal) -> NonFungibleLocalId {
+        let ids: BTreeSet<NonFungibleLocalId> = ids.iter().map(|i| NonFungibleLocalId::integer(*i)).collect();
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, ids.clone())
+            .take_non_fungibles_from_worktop(self.nft_addr, ids, "nft")
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "create_pool", |lookup| (
+                  lookup.bucket("nft"),
+                  lookup.bucket("ccy"),
+                  spot_price,
+                  curve,
+                  spread
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.pool_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn buy_from_pool(&mut self, actor: &Actor, pool: &NonFungibleLocalId, id: &NonFungibleLocalId, amount: Decimal) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "buy_from_pool", |lookup| (
+                  pool.clone(),
+                  id.clone(),
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn sell_to_pool(&mut self, actor: &Actor, pool: &NonFungibleLocalId, id: &NonFungibleLocalId, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "sell_to_pool", |lookup| (
+                  pool.clone(),
+                  lookup.bucket("nft")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn close_pool(&mut self, actor: &Actor, pool: &NonFungibleLocalId) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.pool_addr, BTreeSet::from([pool.clone()]))
+            .take_non_fungibles_from_worktop(self.pool_addr, BTreeSet::from([pool.clone()]), "badge")
+            .call_method_with_name_lookup(self.instance, "close_pool", |lookup| (
+                  lookup.bucket("badge"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn fractionalize(&mut self, actor: &Actor, id: &NonFungibleLocalId, shares: Decimal, reserve_price: Decimal) -> ResourceAddress {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "fractionalize", |lookup| (
+                  lookup.bucket("nft"),
+                  shares,
+                  reserve_price
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().new_resource_addresses()[0]
+    }
+    
+    fn buyout(&mut self, actor: &Actor, share_addr: ResourceAddress, amount: Decimal, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "buyout", |lookup| (
+                  share_addr,
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn redeem_shares(&mut self, actor: &Actor, share_addr: ResourceAddress, amount: Decimal, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, share_addr, amount)
+            .take_all_from_worktop(share_addr, "shares")
+            .call_method_with_name_lookup(self.instance, "redeem_shares", |lookup| (
+                  lookup.bucket("shares"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn last_sale_price(&mut self, actor: &Actor) -> Option<Decimal> {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "last_sale_price", manifest_args!())
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().output(0)
+    }
+    
+    fn floor_price(&mut self, actor: &Actor) -> Option<Decimal> {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "floor_price", manifest_args!())
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().output(0)
+    }
+    
+    fn twap(&mut self, actor: &Actor, window_seconds: i64) -> Option<Decimal> {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "twap", manifest_args!(window_seconds))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().output(0)
+    }
+    
+    fn sales_history(&mut self, actor: &Actor, id: &NonFungibleLocalId, offset: usize, limit: usize) -> Vec<SaleRecord> {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "sales_history", manifest_args!(id.clone(), offset, limit))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().output(0)
+    }
+    
+    fn relist(&mut self, actor: &Actor, badge: &NonFungibleLocalId, terms: ListingTerms, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.badge_addr, BTreeSet::from([badge.clone()]))
+            .take_non_fungibles_from_worktop(self.badge_addr, BTreeSet::from([badge.clone()]), "badge")
+            .call_method_with_name_lookup(self.instance, "relist", |lookup| (
+                  lookup.bucket("badge"),
+                  terms
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn check_event(&mut self, commit_result: &CommitResult, name: &str) {
+        assert!(commit_result.application_events.iter().any(|(id, _)| self.runner.event_name(id) == name));
+    }
+    
+    fn offer(&mut self, actor: &Actor, id: &NonFungibleLocalId) -> Option<(NonFungibleLocalId, Decimal, Option<Instant>)> {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "offer", manifest_args!(id.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().output(0)
+    }
+    
+    fn listing_state(&mut self, actor: &Actor, badge: &NonFungibleLocalId) -> ListingState {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "listing_state", manifest_args!(badge.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().output(0)
+    }
+    
+    // the fee owner moves the market to a new component, the tests then call it
+    fn migrate(&mut self, actor: &Actor, fee_badge: ResourceAddress) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "migrate", manifest_args!())
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt
+    }
+    
+    fn previous(&mut self, actor: &Actor) -> Option<ComponentAddress> {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "previous", manifest_args!())
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().output(0)
+    }
+    
+    fn wind_down(&mut self, actor: &Actor, fee_badge: ResourceAddress) {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "wind_down", manifest_args!())
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn rescue(&mut self, actor: &Actor, fee_badge: ResourceAddress, limit: usize) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "rescue", manifest_args!(limit))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt
+    }
+    
+    // the account refuses (or accepts again) the deposits of third parties
+    fn set_deposit_rule(&mut self, actor: &Actor, rule: DefaultDepositRule) {
+        let transaction = ManifestBuilder::new()
+            .call_method(actor.2, ACCOUNT_SET_DEFAULT_DEPOSIT_RULE_IDENT, manifest_args!(rule))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    // the moderator flags (or unflags) an NFT reported stolen
+    fn flag(&mut self, actor: &Actor, moderator_badge: ResourceAddress, method: &str, item: &NonFungibleGlobalId) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, moderator_badge, dec!(1))
+            .call_method(self.instance, method, manifest_args!(item.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn is_flagged(&mut self, actor: &Actor, item: &NonFungibleGlobalId) -> bool {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "is_flagged", manifest_args!(item.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        receipt.expect_commit_success().output(0)
+    }
+    
+    fn seize_flagged(&mut self, actor: &Actor, moderator_badge: ResourceAddress, item: &NonFungibleGlobalId) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, moderator_badge, dec!(1))
+            .call_method(self.instance, "seize_flagged", manifest_args!(item.clone()))
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn set_trait_fields(&mut self, actor: &Actor, fee_badge: ResourceAddress, fields: Vec<&str>) {
+        let fields: Vec<String> = fields.into_iter().map(|field| field.to_string()).collect();
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "set_trait_fields", manifest_args!(fields))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn place_trait_bid(&mut self, actor: &Actor, amount: Decimal, constraints: Vec<TraitConstraint>) -> NonFungibleLocalId {
+        let receipt = self.place_trait_bid_intern(actor, amount, constraints, false);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.bid_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn place_trait_bid_intern(&mut self, actor: &Actor, amount: Decimal, constraints: Vec<TraitConstraint>, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "place_trait_bid", |lookup| (
+                  lookup.bucket("ccy"),
+                  constraints
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn fill_trait_bid(&mut self, actor: &Actor, bid: &NonFungibleLocalId, id: &NonFungibleLocalId, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "fill_trait_bid", |lookup| (
+                  bid.clone(),
+                  lookup.bucket("nft")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    // calls sell, update, cancel or collect with any items, to exercise the failure paths
+    fn call_with_items(&mut self, actor: &Actor, method: &str, resource: ResourceAddress, ids: Vec<u64>, cost: Option<Decimal>) -> TransactionReceipt {
+        let ids: BTreeSet<NonFungibleLocalId> = ids.into_iter().map(NonFungibleLocalId::integer).collect();
+        self.call_with_ids(actor, method, resource, ids, cost)
+    }
+    
+    fn call_with_ids(&mut self, actor: &Actor, method: &str, resource: ResourceAddress, ids: BTreeSet<NonFungibleLocalId>, cost: Option<Decimal>) -> TransactionReceipt {
+        let builder = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, resource, ids.clone())
+            .take_non_fungibles_from_worktop(resource, ids, "items");
+        let builder = match cost {
+            Some(cost) if method == "sell" => builder.call_method_with_name_lookup(self.instance, method, |lookup| (lookup.bucket("items"), cost, None::<Instant>, None::<ComponentAddress>)),
+            Some(cost) => builder.call_method_with_name_lookup(self.instance, method, |lookup| (lookup.bucket("items"), cost)),
+            None => builder.call_method_with_name_lookup(self.instance, method, |lookup| (lookup.bucket("items"),))
+        };
+        let transaction = builder.deposit_batch(actor.2).build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt
+    }
+    
+    fn buy_with_currency(&mut self, actor: &Actor, id: &NonFungibleLocalId, currency: ResourceAddress, amount: Decimal) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, currency, amount)
+            .take_all_from_worktop(currency, "ccy")
+            .call_method_with_name_lookup(self.instance, "buy", |lookup| (
+                  id.clone(),
+                  lookup.bucket("ccy"),
+                  None::<ManifestProof>,
+                  None::<ManifestProof>
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt
+    }
+    
+    fn sell_raffle(&mut self, actor: &Actor, id: &NonFungibleLocalId, ticket_price: Decimal, ticket_count: u64, deadline: Instant) -> NonFungibleLocalId {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.nft_addr, BTreeSet::from([id.clone()]))
+            .take_non_fungibles_from_worktop(self.nft_addr, BTreeSet::from([id.clone()]), "nft")
+            .call_method_with_name_lookup(self.instance, "sell_raffle", |lookup| (
+                  lookup.bucket("nft"),
+                  ticket_price,
+                  ticket_count,
+                  deadline
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.badge_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn buy_tickets(&mut self, actor: &Actor, id: &NonFungibleLocalId, count: u64, amount: Decimal, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, XRD, amount)
+            .take_all_from_worktop(XRD, "ccy")
+            .call_method_with_name_lookup(self.instance, "buy_tickets", |lookup| (
+                  id.clone(),
+                  count,
+                  lookup.bucket("ccy")
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    fn draw_raffle(&mut self, actor: &Actor, id: &NonFungibleLocalId, should_fail: bool) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .call_method(self.instance, "draw_raffle", manifest_args!(id.clone()))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        if should_fail {
+          receipt.expect_commit_failure();
+        } else {
+          receipt.expect_commit_success();
+        }
+        receipt
+    }
+    
+    // deterministic randomness source of the package, always returning the value, owned by the actor
+    fn fixed_random(&mut self, actor: &Actor, value: u64) -> ComponentAddress {
+        let owner_rule = rule!(require(NonFungibleGlobalId::from_public_key(&actor.0)));
+        let transaction = ManifestBuilder::new()
+            .call_function(self.package, "FixedRandom", "instantiate", manifest_args!(value, owner_rule))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().new_component_addresses()[0]
+    }
+    
+    fn set_randomness(&mut self, actor: &Actor, fee_badge: ResourceAddress, randomness: Option<ComponentAddress>) {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "set_randomness", manifest_args!(randomness))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn set_reward_rates(&mut self, actor: &Actor, fee_badge: ResourceAddress, buyer_rate: Decimal, seller_rate: Decimal, reward_cap: Decimal) {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "set_reward_rates", manifest_args!(buyer_rate, seller_rate))
+            .call_method(self.instance, "set_reward_cap", manifest_args!(reward_cap))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn claim_rewards(&mut self, actor: &Actor, item: &NonFungibleGlobalId) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_non_fungibles(actor.2, item.resource_address(), BTreeSet::from([item.local_id().clone()]))
+            .pop_from_auth_zone("proof")
+            .call_method_with_name_lookup(self.instance, "claim_rewards", |lookup| (
+                  lookup.proof("proof"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn set_discount(&mut self, actor: &Actor, fee_badge: ResourceAddress, resource: ResourceAddress, min_amount: Decimal, fee_rate: Decimal) {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "set_discount", manifest_args!(resource, min_amount, fee_rate))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn set_staking(&mut self, actor: &Actor, fee_badge: ResourceAddress, token: ResourceAddress, staker_share: Decimal) {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "set_staking", manifest_args!(token, staker_share))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn stake_intern(&mut self, actor: &Actor, token: ResourceAddress, amount: Decimal) -> TransactionReceipt {
+        let transaction = ManifestBuilder::new()
+            .withdraw_from_account(actor.2, token, amount)
+            .take_from_worktop(token, amount, "tokens")
+            .call_method_with_name_lookup(self.instance, "stake", |lookup| (
+                  lookup.bucket("tokens"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt
+    }
+    
+    fn stake(&mut self, actor: &Actor, token: ResourceAddress, amount: Decimal) -> NonFungibleLocalId {
+        let receipt = self.stake_intern(actor, token, amount);
+        let result = receipt.expect_commit_success();
+        let changes = self.runner.sum_descendant_balance_changes(result, actor.2.as_node_id());
+        changes.get(&self.stake_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone()
+    }
+    
+    fn call_stake_receipt(&mut self, actor: &Actor, method: &str, receipt_id: &NonFungibleLocalId) -> CommitResult {
+        let transaction = ManifestBuilder::new()
+            .withdraw_non_fungibles_from_account(actor.2, self.stake_addr, BTreeSet::from([receipt_id.clone()]))
+            .take_non_fungibles_from_worktop(self.stake_addr, BTreeSet::from([receipt_id.clone()]), "receipt")
+            .call_method_with_name_lookup(self.instance, method, |lookup| (
+                  lookup.bucket("receipt"),
+                )
+              )
+            .deposit_batch(actor.2)
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success().clone()
+    }
+    
+    fn set_buyer_rule(&mut self, actor: &Actor, fee_badge: ResourceAddress, buyer_rule: Option<AccessRule>) {
+        let transaction = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(actor.2, fee_badge, dec!(1))
+            .call_method(self.instance, "set_buyer_rule", manifest_args!(buyer_rule))
+            .build();
+        let receipt = self.execute(transaction, actor);
+        println!("{:?}\n", receipt);
+        receipt.expect_commit_success();
+    }
+    
+    fn check_nft_received(&mut self, commit_result: &CommitResult, actor: &Actor, item: &NonFungibleGlobalId) {
+        let balances = self.runner.sum_descendant_balance_changes(commit_result, actor.2.as_node_id());
+        let balance = balances.get(&item.resource_address()).expect("no nft received");
+        assert!(balance.clone().added_non_fungibles().contains(item.local_id()));
+    }
+    
+    fn check_balance_change(&mut self, commit_result: &CommitResult, actor: &Actor, ressource: ResourceAddress, exp_amount: Decimal) {
+        let balance_changes = commit_result.vault_balance_changes();
+        for (vault_id, (resource, delta)) in balance_changes.iter() {
+            println!("Vault: {:?}\n   Resource: {:?}\n   Change: {}",
+                vault_id,
+                resource,
+                match delta {
+                    BalanceChange::Fungible(d) => format!("{}", d),
+                    BalanceChange::NonFungible { added, removed } => { format!("+{:?}, -{:?}", added, removed) }
+                }
+            );
+        }
+        let balances = self.runner.sum_descendant_balance_changes(commit_result, actor.2.as_node_id());
+        let zeroBalance = BalanceChange::Fungible(dec!(0));
+        let balance = balances.get(&ressource).unwrap_or(&zeroBalance);
+        let amount = match balance {
+                        BalanceChange::Fungible(d) => d,
+                        _ => panic!("expect fungible")
+                     };
+        println!("{:?}\n", self.runner.sum_descendant_balance_changes(commit_result, actor.2.as_node_id()));
+        assert_eq!(*amount, exp_amount);
+    }
+}
+
+// the market panics with its stable error code, ex: "[E007] invalid badge"
+fn expect_market_error(receipt: &TransactionReceipt, code: u16) {
+    let expected = format!("[E{:03}]", code);
+    receipt.expect_specific_failure(|error| match error {
+        RuntimeError::ApplicationError(ApplicationError::PanicMessage(message)) => message.contains(&expected),
+        _ => false
+    });
+}
+
+#[test]
+fn test_instantiate() {
+    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
+}
+
+#[test]
+fn test_sell() {
+    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.sell(&owner, &id, dec!(5));
+}
+
+#[test]
+fn test_sell_buy() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    env.buy(&buyers[0], &id, dec!(5));
+}
+
+#[test]
+fn test_sell_buy_collect() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    env.buy(&buyers[0], &id, dec!(5));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(5));
+}
+
+#[test]
+fn test_sell_cancel() {
+    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    env.cancel(&owner, &badge);
+}
+
+#[test]
+fn test_sell_cancel_buy_fail() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    env.cancel(&owner, &badge);
+    env.buy_fail(&buyers[0], &id, dec!(5));
+}
+
+#[test]
+fn test_sell_update_buy_collect() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    env.update(&owner, &badge, dec!(4));
+    env.buy(&buyers[0], &id, dec!(4));
+}
+
+#[test]
+fn test_sell_buy_low_fail() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    env.update(&owner, &badge, dec!(6));
+    env.buy_fail(&buyers[0], &id, dec!(5));
+}
+
+#[test]
+fn test_sell_buy_sell_collect() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    env.buy(&buyers[0], &id, dec!(5));
+    let badge2 = env.sell(&buyers[0], &id, dec!(10));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(5));
+}
+
+#[test]
+fn test_fee() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0.25));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(20));
+    env.buy(&buyers[0], &id, dec!(20));
+    let badge2 = env.sell(&buyers[0], &id, dec!(40));
+    env.buy(&buyers[1], &id, dec!(40));
+    let result_collect = env.collect(&owner, &badge);
+    env.check_balance_change(&result_collect, &owner, XRD, dec!(15));
+    let result_collect2 = env.collect(&buyers[0], &badge2);
+    env.check_balance_change(&result_collect2, &buyers[0], XRD, dec!(30));
+    let result_fee = env.collect_fees(&owner, fee_badge);
+    env.check_balance_change(&result_fee, &owner, XRD, dec!(15));
+}
+
+
+#[test]
+fn test_kyc_buy() {
+    let (mut env, owner, buyers, _, _, kyc_badge) = TestEnv::new_with_kyc(dec!(0), true);
+    let id = NonFungibleLocalId::integer(1);
+    env.sell(&owner, &id, dec!(5));
+    env.buy_with_proof(&buyers[0], &id, dec!(5), Some(kyc_badge), false);
+}
+
+#[test]
+fn test_kyc_buy_without_proof_fail() {
+    let (mut env, owner, buyers, _, _, _) = TestEnv::new_with_kyc(dec!(0), true);
+    let id = NonFungibleLocalId::integer(1);
+    env.sell(&owner, &id, dec!(5));
+    let receipt = env.buy_with_proof(&buyers[1], &id, dec!(5), None, true);
+    assert!(format!("{:?}", receipt).contains("AssertAccessRuleFailed"), "buyer without KYC badge should be rejected by the access rule");
+}
+
+#[test]
+fn test_kyc_rule_updated_by_owner() {
+    let (mut env, owner, buyers, _, fee_badge, _) = TestEnv::new_with_kyc(dec!(0), true);
+    let id = NonFungibleLocalId::integer(1);
+    env.sell(&owner, &id, dec!(5));
+    env.buy_fail(&buyers[1], &id, dec!(5));
+    env.set_buyer_rule(&owner, fee_badge, None);
+    env.buy(&buyers[1], &id, dec!(5));
+}
+
+#[test]
+fn test_swap_by_id_collect() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let other_addr = create_non_fungible_tokens(&mut env.runner, &buyers[0], [7].iter());
+    let item = NonFungibleGlobalId::new(other_addr, NonFungibleLocalId::integer(7));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell_swap(&owner, &id, SwapWant::Ids(vec![item.clone()]), dec!(0));
+    env.fill_swap(&buyers[0], &id, &item, dec!(0), false);
+    let result = env.collect(&owner, &badge);
+    env.check_nft_received(&result, &owner, &item);
+}
+
+#[test]
+fn test_swap_by_resource_with_sweetener() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0.1));
+    let other_addr = create_non_fungible_tokens(&mut env.runner, &buyers[0], [7, 8].iter());
+    let item = NonFungibleGlobalId::new(other_addr, NonFungibleLocalId::integer(8));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell_swap(&owner, &id, SwapWant::Resource(other_addr), dec!(10));
+    env.fill_swap(&buyers[0], &id, &item, dec!(10), false);
+    let result = env.collect(&owner, &badge);
+    env.check_nft_received(&result, &owner, &item);
+    env.check_balance_change(&result, &owner, XRD, dec!(9));
+}
+
+#[test]
+fn test_swap_unwanted_item_fail() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let other_addr = create_non_fungible_tokens(&mut env.runner, &buyers[0], [7, 8].iter());
+    let wanted = NonFungibleGlobalId::new(other_addr, NonFungibleLocalId::integer(7));
+    let item = NonFungibleGlobalId::new(other_addr, NonFungibleLocalId::integer(8));
+    let id = NonFungibleLocalId::integer(1);
+    env.sell_swap(&owner, &id, SwapWant::Ids(vec![wanted]), dec!(0));
+    env.fill_swap(&buyers[0], &id, &item, dec!(0), true);
+}
+
+#[test]
+fn test_bundle_buy_collect() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let items: Vec<NonFungibleGlobalId> = [1, 2].iter().map(|i| NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(*i))).collect();
+    let badge = env.sell_bundle(&owner, &items, dec!(8));
+    let receipt = env.buy_bundle(&buyers[0], &badge, dec!(8), false);
+    let result = receipt.expect_commit_success().clone();
+    env.check_nft_received(&result, &buyers[0], &items[0]);
+    env.check_nft_received(&result, &buyers[0], &items[1]);
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(8));
+}
+
+#[test]
+fn test_bundle_multi_collection_cancel() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let other_addr = create_non_fungible_tokens(&mut env.runner, &owner, [7].iter());
+    env.allow_bundle_collection(&owner, fee_badge, other_addr);
+    let items = vec![
+        NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(1)),
+        NonFungibleGlobalId::new(other_addr, NonFungibleLocalId::integer(7))
+    ];
+    let badge = env.sell_bundle(&owner, &items, dec!(8));
+    env.cancel(&owner, &badge);
+    env.buy_bundle(&buyers[0], &badge, dec!(8), true);
+}
+
+// the first item of a bundle shares its local id with a single listing of the market collection
+#[test]
+fn test_bundle_badge_cannot_touch_other_listing() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let other_addr = create_non_fungible_tokens(&mut env.runner, &buyers[1], [1].iter());
+    env.allow_bundle_collection(&owner, fee_badge, other_addr);
+    let id = NonFungibleLocalId::integer(1);
+    env.sell(&owner, &id, dec!(5));
+    let bundle = env.sell_bundle(&buyers[1], &vec![NonFungibleGlobalId::new(other_addr, id.clone())], dec!(8));
+    let badge_addr = env.badge_addr;
+    let receipt = env.call_with_ids(&buyers[1], "update", badge_addr, BTreeSet::from([bundle.clone()]), Some(dec!(1)));
+    expect_market_error(&receipt, 7); // InvalidBadge
+    let receipt = env.cancel_intern(&buyers[1], &bundle, false);
+    env.check_nft_received(&receipt.expect_commit_success().clone(), &buyers[1], &NonFungibleGlobalId::new(other_addr, id.clone()));
+    env.buy(&buyers[0], &id, dec!(5));
+}
+
+#[test]
+fn test_bundle_buy_low_fail() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let items: Vec<NonFungibleGlobalId> = [1, 2, 3].iter().map(|i| NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(*i))).collect();
+    let badge = env.sell_bundle(&owner, &items, dec!(8));
+    env.buy_bundle(&buyers[0], &badge, dec!(7), true);
+}
+
+#[test]
+fn test_rent_reclaim_after_expiry() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0.1));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.list_rental(&owner, &id, dec!(2), 7);
+    let receipt = env.rent(&buyers[0], &id, 5, dec!(10), false);
+    let result = receipt.expect_commit_success().clone();
+    let changes = env.runner.sum_descendant_balance_changes(&result, buyers[0].2.as_node_id());
+    assert_eq!(changes.get(&env.rental_addr).unwrap().clone().added_non_fungibles().len(), 1);
+    env.advance_time(5 * 24 * 3600);
+    let receipt = env.reclaim_rental(&owner, &badge, false);
+    let result = receipt.expect_commit_success().clone();
+    env.check_nft_received(&result, &owner, &NonFungibleGlobalId::new(nft_addr, id));
+    env.check_balance_change(&result, &owner, XRD, dec!(9));
+}
+
+#[test]
+fn test_rental_receipt_copies_nft_data() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new_with_traits(dec!(0));
+    let id = NonFungibleLocalId::integer(2);
+    env.advance_time(0);
+    env.list_rental(&owner, &id, dec!(1), 7);
+    let receipt = env.rent(&buyers[0], &id, 1, dec!(1), false);
+    let result = receipt.expect_commit_success().clone();
+    let changes = env.runner.sum_descendant_balance_changes(&result, buyers[0].2.as_node_id());
+    let receipt_id = changes.get(&env.rental_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone();
+    let data: TraitRentalReceipt = env.runner.get_non_fungible_data(env.rental_addr, receipt_id);
+    assert_eq!(data.nft_id, id);
+    assert_eq!(data.nft_data.background, "gold");
+    assert_eq!(data.nft_data.level, 5);
+}
+
+#[test]
+fn test_reclaim_before_expiry_fail() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.list_rental(&owner, &id, dec!(2), 7);
+    env.rent(&buyers[0], &id, 2, dec!(4), false);
+    env.advance_time(24 * 3600);
+    env.reclaim_rental(&owner, &badge, true);
+    env.rent(&buyers[1], &id, 1, dec!(2), true);
+}
+
+#[test]
+fn test_rent_too_long_fail() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    env.list_rental(&owner, &id, dec!(2), 7);
+    env.rent(&buyers[0], &id, 8, dec!(16), true);
+}
+
+#[test]
+fn test_loan_repay_collect() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let offer = env.offer_loan(&buyers[0], dec!(100), dec!(5), 30);
+    let loan = env.borrow(&owner, &offer, &id);
+    env.advance_time(10 * 24 * 3600);
+    let receipt = env.repay(&owner, &loan, dec!(105), false);
+    let result = receipt.expect_commit_success().clone();
+    env.check_nft_received(&result, &owner, &NonFungibleGlobalId::new(nft_addr, id));
+    let receipt = env.collect_loan(&buyers[0], &offer, false);
+    let result = receipt.expect_commit_success().clone();
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(105));
+}
+
+#[test]
+fn test_loan_default_lender_claims_nft() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let offer = env.offer_loan(&buyers[0], dec!(100), dec!(5), 30);
+    let loan = env.borrow(&owner, &offer, &id);
+    env.collect_loan(&buyers[0], &offer, true);
+    env.advance_time(31 * 24 * 3600);
+    env.repay(&owner, &loan, dec!(105), true);
+    let receipt = env.collect_loan(&buyers[0], &offer, false);
+    let result = receipt.expect_commit_success().clone();
+    env.check_nft_received(&result, &buyers[0], &NonFungibleGlobalId::new(nft_addr, id));
+}
+
+#[test]
+fn test_loan_repay_short_fail() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let offer = env.offer_loan(&buyers[0], dec!(100), dec!(5), 30);
+    let loan = env.borrow(&owner, &offer, &id);
+    env.repay(&owner, &loan, dec!(100), true);
+}
+
+#[test]
+fn test_sealed_auction_second_price() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let (bid_end, reveal_end) = (env.instant_in(3600), env.instant_in(7200));
+    let badge = env.sell_sealed(&owner, &id, dec!(1), bid_end, reveal_end, true);
+    let bid0 = env.sealed_bid(&buyers[0], &id, dec!(10), "salt0", dec!(12));
+    let bid1 = env.sealed_bid(&buyers[1], &id, dec!(8), "salt1", dec!(8));
+    env.advance_time(3600);
+    env.reveal_bid(&buyers[0], &bid0, dec!(10), "salt0", false);
+    env.reveal_bid(&buyers[1], &bid1, dec!(8), "salt1", false);
+    env.advance_time(3600);
+    env.settle_sealed(&owner, &id);
+    let result = env.claim_bid(&buyers[0], &bid0);
+    env.check_nft_received(&result, &buyers[0], &NonFungibleGlobalId::new(nft_addr, id));
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(4));
+    let result = env.claim_bid(&buyers[1], &bid1);
+    env.check_balance_change(&result, &buyers[1], XRD, dec!(8));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(8));
+}
+
+#[test]
+fn test_sealed_auction_first_price() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let (bid_end, reveal_end) = (env.instant_in(3600), env.instant_in(7200));
+    let badge = env.sell_sealed(&owner, &id, dec!(1), bid_end, reveal_end, false);
+    let bid0 = env.sealed_bid(&buyers[0], &id, dec!(10), "salt0", dec!(10));
+    env.sealed_bid(&buyers[1], &id, dec!(8), "salt1", dec!(8));
+    env.advance_time(3600);
+    env.reveal_bid(&buyers[0], &bid0, dec!(10), "salt0", false);
+    env.advance_time(3600);
+    env.settle_sealed(&owner, &id);
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(10));
+}
+
+#[test]
+fn test_sealed_reveal_mismatch_fail() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let (bid_end, reveal_end) = (env.instant_in(3600), env.instant_in(7200));
+    env.sell_sealed(&owner, &id, dec!(1), bid_end, reveal_end, false);
+    let bid0 = env.sealed_bid(&buyers[0], &id, dec!(10), "salt0", dec!(10));
+    env.reveal_bid(&buyers[0], &bid0, dec!(10), "salt0", true);
+    env.advance_time(3600);
+    env.reveal_bid(&buyers[0], &bid0, dec!(9), "salt0", true);
+    env.reveal_bid(&buyers[0], &bid0, dec!(10), "other", true);
+}
+
+fn auction_terms(env: &TestEnv, reserve_price: Decimal, buy_now_price: Option<Decimal>) -> AuctionTerms {
+    AuctionTerms {
+        start_price: dec!(1),
+        reserve_price,
+        public_reserve: true,
+        buy_now_price,
+        end_time: env.instant_in(3600),
+        extension_minutes: 10
+    }
+}
+
+#[test]
+fn test_auction_settle_collect() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let terms = auction_terms(&env, dec!(5), None);
+    let badge = env.sell_auction(&owner, &id, terms);
+    let bid0 = env.bid(&buyers[0], &id, dec!(6));
+    env.bid_fail(&buyers[1], &id, dec!(6));
+    let bid1 = env.bid(&buyers[1], &id, dec!(7));
+    env.advance_time(3600);
+    env.bid_fail(&buyers[0], &id, dec!(8));
+    env.settle_auction(&owner, &id);
+    let result = env.claim_bid(&buyers[0], &bid0);
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(6));
+    let result = env.claim_bid(&buyers[1], &bid1);
+    env.check_nft_received(&result, &buyers[1], &NonFungibleGlobalId::new(nft_addr, id));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(7));
+}
+
+#[test]
+fn test_auction_reserve_not_met() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let terms = auction_terms(&env, dec!(10), None);
+    let badge = env.sell_auction(&owner, &id, terms);
+    let bid0 = env.bid(&buyers[0], &id, dec!(6));
+    env.cancel_fail(&owner, &badge);
+    env.advance_time(3600);
+    env.settle_auction(&owner, &id);
+    let result = env.claim_bid(&buyers[0], &bid0);
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(6));
+    env.cancel(&owner, &badge);
+}
+
+#[test]
+fn test_auction_buy_now() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let terms = auction_terms(&env, dec!(5), Some(dec!(20)));
+    let badge = env.sell_auction(&owner, &id, terms);
+    let bid0 = env.bid(&buyers[0], &id, dec!(6));
+    env.buy(&buyers[1], &id, dec!(20));
+    env.bid_fail(&buyers[0], &id, dec!(8));
+    let result = env.claim_bid(&buyers[0], &bid0);
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(6));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(20));
+}
+
+#[test]
+fn test_auction_buy_now_after_bid_above_price_fail() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let terms = auction_terms(&env, dec!(5), Some(dec!(20)));
+    env.sell_auction(&owner, &id, terms);
+    env.bid(&buyers[0], &id, dec!(25));
+    let receipt = env.buy_full(&buyers[1], &id, dec!(20), None, None, None, true);
+    expect_market_error(&receipt, 92); // BuyNowOutbid
+}
+
+#[test]
+fn test_auction_anti_sniping() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let terms = auction_terms(&env, dec!(5), None);
+    env.sell_auction(&owner, &id, terms);
+    env.advance_time(3300);
+    env.bid(&buyers[0], &id, dec!(6));
+    env.advance_time(360);
+    env.bid(&buyers[1], &id, dec!(7));
+    env.advance_time(600);
+    env.bid_fail(&buyers[0], &id, dec!(8));
+}
+
+#[test]
+fn test_pool_linear_spread() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let pool = env.create_pool(&owner, &[1, 2], dec!(0), dec!(10), Curve::Linear(dec!(1)), dec!(0.1));
+    let result = env.buy_from_pool(&buyers[0], &pool, &id, dec!(20));
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(-11));
+    let receipt = env.sell_to_pool(&buyers[0], &pool, &id, false);
+    env.check_balance_change(&receipt.expect_commit_success().clone(), &buyers[0], XRD, dec!(10));
+    let result = env.close_pool(&owner, &pool);
+    env.check_balance_change(&result, &owner, XRD, dec!(1));
+    env.check_nft_received(&result, &owner, &NonFungibleGlobalId::new(nft_addr, id));
+}
+
+#[test]
+fn test_pool_exponential_fee() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0.1));
+    let pool = env.create_pool(&owner, &[1, 2], dec!(0), dec!(4), Curve::Exponential(dec!(2)), dec!(0));
+    let result = env.buy_from_pool(&buyers[0], &pool, &NonFungibleLocalId::integer(1), dec!(20));
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(-4.4));
+    let result = env.buy_from_pool(&buyers[1], &pool, &NonFungibleLocalId::integer(2), dec!(20));
+    env.check_balance_change(&result, &buyers[1], XRD, dec!(-8.8));
+    let result = env.collect_fees(&owner, fee_badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(1.2));
+}
+
+#[test]
+fn test_pool_sell_without_currency_fail() {
+    let (mut env, owner, _, _, _) = TestEnv::new(dec!(0));
+    let pool = env.create_pool(&owner, &[1], dec!(0), dec!(4), Curve::Linear(dec!(1)), dec!(0));
+    env.sell_to_pool(&owner, &pool, &NonFungibleLocalId::integer(2), true);
+}
+
+#[test]
+fn test_oracle_prices() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    env.advance_time(0);
+    assert_eq!(env.last_sale_price(&owner), None);
+    assert_eq!(env.twap(&owner, 3600), None);
+    env.sell(&owner, &NonFungibleLocalId::integer(1), dec!(5));
+    env.sell(&owner, &NonFungibleLocalId::integer(2), dec!(10));
+    env.sell(&owner, &NonFungibleLocalId::integer(3), dec!(20));
+    assert_eq!(env.floor_price(&owner), Some(dec!(5)));
+    env.buy(&buyers[0], &NonFungibleLocalId::integer(1), dec!(5));
+    env.advance_time(3600);
+    env.buy(&buyers[0], &NonFungibleLocalId::integer(2), dec!(10));
+    env.advance_time(3600);
+    assert_eq!(env.last_sale_price(&owner), Some(dec!(10)));
+    assert_eq!(env.floor_price(&owner), Some(dec!(20)));
+    assert_eq!(env.twap(&owner, 7200), Some(dec!(7.5)));
+    assert_eq!(env.twap(&owner, 3600), Some(dec!(10)));
+}
+
+#[test]
+fn test_sales_history() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0.25));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    assert!(env.sales_history(&owner, &id, 0, 10).is_empty());
+    let badge = env.sell(&owner, &id, dec!(20));
+    env.buy(&buyers[0], &id, dec!(20));
+    env.advance_time(60);
+    let badge2 = env.sell(&buyers[0], &id, dec!(40));
+    env.buy(&buyers[1], &id, dec!(40));
+    let history = env.sales_history(&owner, &id, 0, 10);
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].price, dec!(20));
+    assert_eq!(history[0].fee, dec!(5));
+    assert_eq!(history[0].seller_badge, badge);
+    assert_eq!(history[0].buyer, None);
+    assert!(history[0].time.seconds_since_unix_epoch < history[1].time.seconds_since_unix_epoch);
+    let page = env.sales_history(&owner, &id, 1, 1);
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].price, dec!(40));
+    assert_eq!(page[0].seller_badge, badge2);
+}
+
+#[test]
+fn test_sales_history_auction_buyer() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let terms = auction_terms(&env, dec!(5), None);
+    env.sell_auction(&owner, &id, terms);
+    let bid0 = env.bid(&buyers[0], &id, dec!(6));
+    env.advance_time(3600);
+    env.settle_auction(&owner, &id);
+    let history = env.sales_history(&owner, &id, 0, 10);
+    assert_eq!(history[0].buyer, Some(NonFungibleGlobalId::new(env.bid_addr, bid0)));
+    assert_eq!(env.last_sale_price(&owner), Some(dec!(6)));
+}
+
+fn trait_constraint(field: &str, value: &str) -> TraitConstraint {
+    TraitConstraint { field: field.to_string(), value: value.to_string() }
+}
+
+#[test]
+fn test_trait_bid_fill_claim() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new_with_traits(dec!(0));
+    env.set_trait_fields(&owner, fee_badge, vec!["background", "level"]);
+    let bid = env.place_trait_bid(&buyers[0], dec!(10), vec![trait_constraint("background", "gold"), trait_constraint("level", "5")]);
+    env.fill_trait_bid(&owner, &bid, &NonFungibleLocalId::integer(1), true);
+    env.fill_trait_bid(&owner, &bid, &NonFungibleLocalId::integer(3), true);
+    let receipt = env.fill_trait_bid(&owner, &bid, &NonFungibleLocalId::integer(2), false);
+    env.check_balance_change(&receipt.expect_commit_success().clone(), &owner, XRD, dec!(10));
+    let result = env.claim_bid(&buyers[0], &bid);
+    env.check_nft_received(&result, &buyers[0], &NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(2)));
+}
+
+#[test]
+fn test_trait_bid_on_dropped_field_fail() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new_with_traits(dec!(0));
+    env.set_trait_fields(&owner, fee_badge, vec!["background", "level"]);
+    let bid = env.place_trait_bid(&buyers[0], dec!(10), vec![trait_constraint("level", "5")]);
+    env.set_trait_fields(&owner, fee_badge, vec!["background"]);
+    let receipt = env.fill_trait_bid(&owner, &bid, &NonFungibleLocalId::integer(2), true);
+    expect_market_error(&receipt, 56); // UnknownTraitField
+}
+
+#[test]
+fn test_trait_bid_without_constraints() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new_with_traits(dec!(0.1));
+    let bid = env.place_trait_bid(&buyers[0], dec!(10), vec![]);
+    let receipt = env.fill_trait_bid(&owner, &bid, &NonFungibleLocalId::integer(3), false);
+    env.check_balance_change(&receipt.expect_commit_success().clone(), &owner, XRD, dec!(9));
+}
+
+#[test]
+fn test_error_sell() {
+    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
+    let receipt = env.call_with_items(&owner, "sell", nft_addr, vec![1], Some(dec!(-1)));
+    expect_market_error(&receipt, 6); // NegativeCost
+    let other_addr = create_non_fungible_tokens(&mut env.runner, &owner, [7].iter());
+    let receipt = env.call_with_items(&owner, "sell", other_addr, vec![7], Some(dec!(5)));
+    expect_market_error(&receipt, 1); // WrongNftResource
+    let receipt = env.call_with_items(&owner, "sell", nft_addr, vec![1, 2], Some(dec!(5)));
+    expect_market_error(&receipt, 5); // SingleItemExpected
+}
+
+// a bucket of several NFTs is rejected where a single one is expected
+#[test]
+fn test_error_borrow_several_items() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let offer = env.offer_loan(&buyers[0], dec!(100), dec!(5), 30);
+    let ids = BTreeSet::from([NonFungibleLocalId::integer(1), NonFungibleLocalId::integer(2)]);
+    let transaction = ManifestBuilder::new()
+        .withdraw_non_fungibles_from_account(owner.2, nft_addr, ids.clone())
+        .take_non_fungibles_from_worktop(nft_addr, ids, "nft")
+        .call_method_with_name_lookup(env.instance, "borrow", |lookup| (
+              offer.clone(),
+              lookup.bucket("nft")
+            )
+          )
+        .deposit_batch(owner.2)
+        .build();
+    let receipt = env.execute(transaction, &owner);
+    expect_market_error(&receipt, 5); // SingleItemExpected
+}
+
+#[test]
+fn test_error_update() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    let badge_addr = env.badge_addr;
+    let receipt = env.call_with_ids(&owner, "update", badge_addr, BTreeSet::from([badge.clone()]), Some(dec!(-1)));
+    expect_market_error(&receipt, 6); // NegativeCost
+    let receipt = env.call_with_items(&owner, "update", nft_addr, vec![2], Some(dec!(4)));
+    expect_market_error(&receipt, 2); // WrongBadgeResource
+    env.buy(&buyers[0], &id, dec!(5));
+    let receipt = env.call_with_ids(&owner, "update", badge_addr, BTreeSet::from([badge]), Some(dec!(4)));
+    expect_market_error(&receipt, 58); // ListingSold
+}
+
+#[test]
+fn test_error_cancel() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.sell(&owner, &id, dec!(5));
+    let badge2 = env.sell(&owner, &NonFungibleLocalId::integer(2), dec!(5));
+    let badge_addr = env.badge_addr;
+    let receipt = env.call_with_items(&owner, "cancel", nft_addr, vec![3], None);
+    expect_market_error(&receipt, 2); // WrongBadgeResource
+    let receipt = env.call_with_ids(&owner, "cancel", badge_addr, BTreeSet::from([badge, badge2]), None);
+    expect_market_error(&receipt, 5); // SingleItemExpected
+    let terms = auction_terms(&env, dec!(5), None);
+    let badge3 = env.sell_auction(&owner, &NonFungibleLocalId::integer(3), terms);
+    env.bid(&buyers[0], &NonFungibleLocalId::integer(3), dec!(6));
+    let receipt = env.cancel_intern(&owner, &badge3, true);
+    expect_market_error(&receipt, 39); // AuctionHasBids
+}
+
+#[test]
+fn test_error_collect() {
+    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    let badge_addr = env.badge_addr;
+    let receipt = env.call_with_ids(&owner, "collect", badge_addr, BTreeSet::from([badge]), None);
+    expect_market_error(&receipt, 10); // NothingToCollect
+    let receipt = env.call_with_items(&owner, "collect", nft_addr, vec![2], None);
+    expect_market_error(&receipt, 2); // WrongBadgeResource
+}
+
+#[test]
+fn test_error_buy() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let receipt = env.buy_with_proof(&buyers[0], &id, dec!(5), None, true);
+    expect_market_error(&receipt, 8); // NotListed
+    env.sell(&owner, &id, dec!(5));
+    let receipt = env.buy_with_proof(&buyers[0], &id, dec!(4), None, true);
+    expect_market_error(&receipt, 11); // InsufficientPayment
+    let other_ccy = create_fungible_tokens(&mut env.runner, &buyers[0], dec!(100));
+    let receipt = env.buy_with_currency(&buyers[0], &id, other_ccy, dec!(5));
+    expect_market_error(&receipt, 4); // WrongCurrency
+    let terms = auction_terms(&env, dec!(5), None);
+    env.sell_auction(&owner, &NonFungibleLocalId::integer(2), terms);
+    let receipt = env.buy_with_proof(&buyers[0], &NonFungibleLocalId::integer(2), dec!(5), None, true);
+    expect_market_error(&receipt, 44); // NoBuyNowPrice
+}
+
+#[test]
+fn test_sold_listing_cancel_collects() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Active);
+    env.buy(&buyers[0], &id, dec!(5));
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Sold);
+    let receipt = env.cancel_intern(&owner, &badge, false);
+    env.check_balance_change(receipt.expect_commit_success(), &owner, XRD, dec!(5));
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Sold);
+}
+
+#[test]
+fn test_expired_auction_update_cancel() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let terms = auction_terms(&env, dec!(10), None);
+    let badge = env.sell_auction(&owner, &id, terms);
+    let receipt = env.call_with_ids(&owner, "update", env.badge_addr, BTreeSet::from([badge.clone()]), Some(dec!(4)));
+    expect_market_error(&receipt, 61); // FixedPriceOnly
+    env.bid(&buyers[0], &id, dec!(6));
+    env.advance_time(3600);
+    env.settle_auction(&owner, &id);
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Expired);
+    let receipt = env.call_with_ids(&owner, "update", env.badge_addr, BTreeSet::from([badge.clone()]), Some(dec!(4)));
+    expect_market_error(&receipt, 60); // ListingExpired
+    env.cancel(&owner, &badge);
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Cancelled);
+}
+
+#[test]
+fn test_rental_collect_fail_cancel() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.list_rental(&owner, &id, dec!(2), 7);
+    env.rent(&buyers[0], &id, 1, dec!(2), false);
+    let receipt = env.call_with_ids(&owner, "collect", env.badge_addr, BTreeSet::from([badge.clone()]), None);
+    expect_market_error(&receipt, 10); // NothingToCollect
+    let receipt = env.cancel_intern(&owner, &badge, true);
+    expect_market_error(&receipt, 20); // RentalOngoing
+    env.advance_time(24 * 3600);
+    let receipt = env.cancel_intern(&owner, &badge, false);
+    let result = receipt.expect_commit_success();
+    env.check_nft_received(result, &owner, &NonFungibleGlobalId::new(nft_addr, id));
+    env.check_balance_change(result, &owner, XRD, dec!(2));
+}
+
+#[test]
+fn test_relist_fixed_price_to_auction() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.sell(&owner, &id, dec!(5));
+    let terms = auction_terms(&env, dec!(5), None);
+    let receipt = env.relist(&owner, &badge, ListingTerms::Auction(terms), false);
+    env.check_event(receipt.expect_commit_success(), "ListingUpdatedEvent");
+    let receipt = env.buy_with_proof(&buyers[0], &id, dec!(5), None, true);
+    expect_market_error(&receipt, 44); // NoBuyNowPrice
+    env.bid(&buyers[0], &id, dec!(6));
+    env.advance_time(3600);
+    env.settle_auction(&owner, &id);
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(6));
+}
+
+#[test]
+fn test_relist_expired_auction_to_swap() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let terms = auction_terms(&env, dec!(10), None);
+    let badge = env.sell_auction(&owner, &id, terms);
+    env.bid(&buyers[0], &id, dec!(6));
+    let receipt = env.relist(&owner, &badge, ListingTerms::FixedPrice(dec!(8)), true);
+    expect_market_error(&receipt, 39); // AuctionHasBids
+    env.advance_time(3600);
+    env.settle_auction(&owner, &id);
+    let item = NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(2));
+    env.relist(&owner, &badge, ListingTerms::Swap(SwapWant::Ids(vec![item]), dec!(0)), false);
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Active);
+}
+
+#[test]
+fn test_relist_in_other_currency() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0.1));
+    let id = NonFungibleLocalId::integer(1);
+    let token = create_fungible_tokens(&mut env.runner, &buyers[0], dec!(100));
+    let badge = env.sell(&owner, &id, dec!(10));
+    env.relist(&owner, &badge, ListingTerms::FixedPriceIn(token, dec!(20)), false);
+    assert_eq!(env.floor_price(&owner), None);
+    let receipt = env.buy_full(&buyers[0], &id, dec!(20), None, None, None, true);
+    expect_market_error(&receipt, 4); // WrongCurrency
+    let receipt = env.buy_with_currency(&buyers[0], &id, token, dec!(20));
+    env.check_nft_received(receipt.expect_commit_success(), &buyers[0], &NonFungibleGlobalId::new(nft_addr, id));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, token, dec!(18));
+    let result = env.collect_currency_fees(&owner, fee_badge, token);
+    env.check_balance_change(&result, &owner, token, dec!(2));
+}
+
+#[test]
+fn test_relist_sold_fail() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(5));
+    env.buy(&buyers[0], &id, dec!(5));
+    let receipt = env.relist(&owner, &badge, ListingTerms::FixedPrice(dec!(8)), true);
+    expect_market_error(&receipt, 58); // ListingSold
+}
+
+#[test]
+fn test_scheduled_sell_buy_after_start() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let starts_at = env.instant_in(3600);
+    let badge = env.sell_scheduled(&owner, &id, dec!(5), Some(starts_at));
+    assert_eq!(env.offer(&owner, &id), Some((badge.clone(), dec!(5), Some(starts_at))));
+    assert_eq!(env.floor_price(&owner), None);
+    let receipt = env.buy_with_proof(&buyers[0], &id, dec!(5), None, true);
+    expect_market_error(&receipt, 63); // NotLiveYet
+    env.update(&owner, &badge, dec!(4));
+    env.advance_time(3600);
+    assert_eq!(env.floor_price(&owner), Some(dec!(4)));
+    env.buy(&buyers[0], &id, dec!(4));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(4));
+}
+
+#[test]
+fn test_scheduled_sell_cancel_before_start() {
+    let (mut env, owner, _, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let starts_at = env.instant_in(3600);
+    let badge = env.sell_scheduled(&owner, &id, dec!(5), Some(starts_at));
+    let receipt = env.cancel_intern(&owner, &badge, false);
+    env.check_nft_received(receipt.expect_commit_success(), &owner, &NonFungibleGlobalId::new(nft_addr, id.clone()));
+    assert_eq!(env.offer(&owner, &id), None);
+}
+
+#[test]
+fn test_fraction_buyout_redeem_pro_rata() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0.1));
+    let id = NonFungibleLocalId::integer(1);
+    let share_addr = env.fractionalize(&owner, &id, dec!(100), dec!(50));
+    transfert_tokens(&mut env.runner, share_addr, dec!(40), &owner, &buyers[1]);
+    env.buyout(&buyers[2], share_addr, dec!(40), true);
+    let receipt = env.buyout(&buyers[2], share_addr, dec!(50), false);
+    env.check_nft_received(receipt.expect_commit_success(), &buyers[2], &NonFungibleGlobalId::new(nft_addr, id));
+    let receipt = env.buyout(&buyers[0], share_addr, dec!(50), true);
+    expect_market_error(&receipt, 65); // BoughtOut
+    let receipt = env.redeem_shares(&buyers[1], share_addr, dec!(40), false);
+    env.check_balance_change(receipt.expect_commit_success(), &buyers[1], XRD, dec!(18));
+    let receipt = env.redeem_shares(&owner, share_addr, dec!(60), false);
+    env.check_balance_change(receipt.expect_commit_success(), &owner, XRD, dec!(27));
+}
+
+#[test]
+fn test_fraction_redeem_all_shares() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let share_addr = env.fractionalize(&owner, &id, dec!(10), dec!(50));
+    transfert_tokens(&mut env.runner, share_addr, dec!(1), &owner, &buyers[0]);
+    let receipt = env.redeem_shares(&owner, share_addr, dec!(9), true);
+    expect_market_error(&receipt, 66); // PartialShares
+    transfert_tokens(&mut env.runner, share_addr, dec!(1), &buyers[0], &owner);
+    let receipt = env.redeem_shares(&owner, share_addr, dec!(10), false);
+    env.check_nft_received(receipt.expect_commit_success(), &owner, &NonFungibleGlobalId::new(nft_addr, id));
+    env.buyout(&buyers[1], share_addr, dec!(50), true);
+}
+
+fn layaway_terms() -> LayawayTerms {
+    LayawayTerms {
+        deposit: dec!(10),
+        installments: 2,
+        interval_days: 7,
+        forfeit_rate: dec!(0.5)
+    }
+}
+
+#[test]
+fn test_layaway_paid_in_full() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.sell(&owner, &id, dec!(30));
+    env.offer_layaway(&owner, &badge, layaway_terms());
+    let receipt_id = env.start_layaway(&buyers[0], &id, dec!(10));
+    let receipt = env.buy_with_proof(&buyers[1], &id, dec!(30), None, true);
+    expect_market_error(&receipt, 8); // NotListed
+    let receipt = env.call_with_ids(&owner, "update", env.badge_addr, BTreeSet::from([badge.clone()]), Some(dec!(40)));
+    expect_market_error(&receipt, 68); // LayawayOngoing
+    env.advance_time(6 * 24 * 3600);
+    env.pay_installment(&buyers[0], &receipt_id, dec!(10), false);
+    env.advance_time(6 * 24 * 3600);
+    env.pay_installment(&buyers[0], &receipt_id, dec!(10), false);
+    let result = env.claim_bid(&buyers[0], &receipt_id);
+    env.check_nft_received(&result, &buyers[0], &NonFungibleGlobalId::new(nft_addr, id));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(30));
+}
+
+#[test]
+fn test_layaway_on_escrowed_listing_fail() {
+    let (mut env, owner, _, _, _) = TestEnv::new(dec!(0));
+    let badge = env.sell_escrowed(&owner, &NonFungibleLocalId::integer(1), dec!(30), 7);
+    let receipt = env.offer_layaway_intern(&owner, &badge, layaway_terms(), true);
+    expect_market_error(&receipt, 94); // EscrowedListing
+}
+
+#[test]
+fn test_layaway_default_forfeits_deposit() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.sell(&owner, &id, dec!(30));
+    env.offer_layaway(&owner, &badge, layaway_terms());
+    let receipt_id = env.start_layaway(&buyers[0], &id, dec!(10));
+    env.advance_time(8 * 24 * 3600);
+    let receipt = env.pay_installment(&buyers[0], &receipt_id, dec!(10), true);
+    expect_market_error(&receipt, 70); // LayawayDefaulted
+    env.default_layaway(&buyers[1], &receipt_id);
+    let result = env.claim_bid(&buyers[0], &receipt_id);
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(5));
+    env.buy(&buyers[1], &id, dec!(30));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(35));
+}
+
+#[test]
+fn test_raffle_sold_out_draw() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0.1));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let randomness = env.fixed_random(&owner, 2);
+    env.set_randomness(&owner, fee_badge, Some(randomness));
+    let deadline = env.instant_in(3600);
+    let badge = env.sell_raffle(&owner, &id, dec!(10), 3, deadline);
+    env.buy_tickets(&buyers[0], &id, 2, dec!(20), false);
+    let receipt = env.draw_raffle(&owner, &id, true);
+    expect_market_error(&receipt, 76); // RaffleNotOver
+    let receipt = env.buy_tickets(&buyers[1], &id, 1, dec!(10), false);
+    let result = receipt.expect_commit_success();
+    let changes = env.runner.sum_descendant_balance_changes(result, buyers[1].2.as_node_id());
+    let ticket = changes.get(&env.bid_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone();
+    let receipt = env.buy_tickets(&buyers[2], &id, 1, dec!(10), true);
+    expect_market_error(&receipt, 75); // RaffleSoldOut
+    env.draw_raffle(&owner, &id, false);
+    let result = env.claim_bid(&buyers[1], &ticket);
+    env.check_nft_received(&result, &buyers[1], &NonFungibleGlobalId::new(nft_addr, id));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(27));
+}
+
+#[test]
+fn test_raffle_deadline_without_tickets() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let deadline = env.instant_in(3600);
+    let badge = env.sell_raffle(&owner, &id, dec!(10), 3, deadline);
+    env.advance_time(3600);
+    let receipt = env.buy_tickets(&buyers[0], &id, 1, dec!(10), true);
+    expect_market_error(&receipt, 74); // RaffleEnded
+    env.draw_raffle(&owner, &id, false);
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Expired);
+    let receipt = env.cancel_intern(&owner, &badge, false);
+    env.check_nft_received(receipt.expect_commit_success(), &owner, &NonFungibleGlobalId::new(nft_addr, id));
+}
+
+// without a randomness component the fee owner draws, the fixed value only changes with its owner
+#[test]
+fn test_raffle_draw_restricted_without_randomness() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let deadline = env.instant_in(3600);
+    env.sell_raffle(&owner, &id, dec!(10), 1, deadline);
+    env.buy_tickets(&buyers[0], &id, 1, dec!(10), false);
+    env.draw_raffle(&buyers[0], &id, true);
+    let transaction = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(owner.2, fee_badge, dec!(1))
+        .call_method(env.instance, "draw_raffle", manifest_args!(id.clone()))
+        .build();
+    env.execute(transaction, &owner).expect_commit_success();
+    
+    let randomness = env.fixed_random(&owner, 2);
+    let transaction = ManifestBuilder::new()
+        .call_method(randomness, "set_value", manifest_args!(0u64))
+        .build();
+    env.execute(transaction, &buyers[0]).expect_commit_failure();
+}
+
+#[test]
+fn test_rewards_accrued_and_capped() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0));
+    let card_addr = create_non_fungible_tokens(&mut env.runner, &buyers[0], [1].iter());
+    let card = NonFungibleGlobalId::new(card_addr, NonFungibleLocalId::integer(1));
+    env.set_reward_rates(&owner, fee_badge, dec!(1), dec!(2), dec!(25));
+    let badge = env.sell(&owner, &NonFungibleLocalId::integer(1), dec!(5));
+    env.buy_as(&buyers[0], &NonFungibleLocalId::integer(1), dec!(5), &card);
+    let reward_addr = env.reward_addr;
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, reward_addr, dec!(10));
+    let badge = env.sell(&owner, &NonFungibleLocalId::integer(2), dec!(5));
+    env.buy_as(&buyers[0], &NonFungibleLocalId::integer(2), dec!(5), &card);
+    let result = env.claim_rewards(&buyers[0], &card);
+    env.check_balance_change(&result, &buyers[0], reward_addr, dec!(10));
+    let result = env.claim_rewards(&owner, &NonFungibleGlobalId::new(env.badge_addr, badge));
+    env.check_balance_change(&result, &owner, reward_addr, dec!(5));
+}
+
+#[test]
+fn test_rewards_of_registered_seller_account() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0));
+    env.set_reward_rates(&owner, fee_badge, dec!(0), dec!(2), dec!(100));
+    let badge = env.sell_registered(&owner, &NonFungibleLocalId::integer(1), dec!(5));
+    env.buy(&buyers[0], &NonFungibleLocalId::integer(1), dec!(5));
+    env.collect(&owner, &badge);
+    let transaction = ManifestBuilder::new()
+        .call_method(env.instance, "claim_account_rewards", manifest_args!(owner.2))
+        .build();
+    let receipt = env.execute(transaction, &buyers[1]);
+    let result = receipt.expect_commit_success().clone();
+    let reward_addr = env.reward_addr;
+    env.check_balance_change(&result, &owner, reward_addr, dec!(10));
+}
+
+#[test]
+fn test_rewards_buyer_in_history() {
+    let (mut env, owner, buyers, _, _) = TestEnv::new(dec!(0));
+    let card_addr = create_non_fungible_tokens(&mut env.runner, &buyers[0], [1].iter());
+    let card = NonFungibleGlobalId::new(card_addr, NonFungibleLocalId::integer(1));
+    let id = NonFungibleLocalId::integer(1);
+    env.sell(&owner, &id, dec!(5));
+    env.buy_as(&buyers[0], &id, dec!(5), &card);
+    let history = env.sales_history(&owner, &id, 0, 10);
+    assert_eq!(history[0].buyer, Some(card));
+}
+
+#[test]
+fn test_discounted_fee() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0.1));
+    let loyalty_addr = create_fungible_tokens(&mut env.runner, &buyers[0], dec!(10));
+    env.set_discount(&owner, fee_badge, loyalty_addr, dec!(5), dec!(0.01));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(100));
+    let receipt = env.buy_discounted(&buyers[0], &id, dec!(100), (loyalty_addr, dec!(5)));
+    env.check_event(receipt.expect_commit_success(), "SoldEvent");
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(99));
+}
+
+#[test]
+fn test_discount_below_minimum_amount() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0.1));
+    let loyalty_addr = create_fungible_tokens(&mut env.runner, &buyers[0], dec!(10));
+    env.set_discount(&owner, fee_badge, loyalty_addr, dec!(5), dec!(0.01));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(100));
+    env.buy_discounted(&buyers[0], &id, dec!(100), (loyalty_addr, dec!(2)));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(90));
+}
+
+#[test]
+fn test_stakers_share_buy_fees() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0.1));
+    let gov_addr = create_fungible_tokens(&mut env.runner, &buyers[0], dec!(40));
+    transfert_tokens(&mut env.runner, gov_addr, dec!(10), &buyers[0], &buyers[1]);
+    env.set_staking(&owner, fee_badge, gov_addr, dec!(0.5));
+    let stake0 = env.stake(&buyers[0], gov_addr, dec!(30));
+    let stake1 = env.stake(&buyers[1], gov_addr, dec!(10));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell(&owner, &id, dec!(100));
+    env.buy(&buyers[2], &id, dec!(100));
+    let result = env.call_stake_receipt(&buyers[0], "claim_stake_rewards", &stake0);
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(3.75));
+    let result = env.call_stake_receipt(&buyers[1], "unstake", &stake1);
+    env.check_balance_change(&result, &buyers[1], XRD, dec!(1.25));
+    env.check_balance_change(&result, &buyers[1], gov_addr, dec!(10));
+    let result = env.call_stake_receipt(&buyers[0], "unstake", &stake0);
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(0));
+    env.check_balance_change(&result, &buyers[0], gov_addr, dec!(30));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(90));
+}
+
+#[test]
+fn test_stake_disabled_or_wrong_token() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0.1));
+    let gov_addr = create_fungible_tokens(&mut env.runner, &buyers[0], dec!(10));
+    let other_addr = create_fungible_tokens(&mut env.runner, &buyers[0], dec!(10));
+    let receipt = env.stake_intern(&buyers[0], gov_addr, dec!(5));
+    expect_market_error(&receipt, 79); // StakingDisabled
+    env.set_staking(&owner, fee_badge, gov_addr, dec!(0.5));
+    let receipt = env.stake_intern(&buyers[0], other_addr, dec!(5));
+    expect_market_error(&receipt, 80); // WrongStakeToken
+}
+
+#[test]
+fn test_escrow_confirmed_by_buyer() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0.1));
+    let id = NonFungibleLocalId::integer(1);
+    let badge = env.sell_escrowed(&owner, &id, dec!(100), 7);
+    let receipt_id = env.buy_escrowed(&buyers[0], &id, dec!(100));
+    let badge_addr = env.badge_addr;
+    let receipt = env.call_with_ids(&owner, "collect", badge_addr, BTreeSet::from([badge.clone()]), None);
+    expect_market_error(&receipt, 10); // NothingToCollect
+    let receipt = env.call_with_ids(&owner, "cancel", badge_addr, BTreeSet::from([badge.clone()]), None);
+    expect_market_error(&receipt, 82); // EscrowOngoing
+    let result = env.confirm_escrow(&buyers[0], &receipt_id);
+    env.check_nft_received(&result, &buyers[0], &NonFungibleGlobalId::new(nft_addr, id));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(90));
+}
+
+#[test]
+fn test_escrow_released_after_timeout() {
+    let (mut env, owner, buyers, nft_addr, _) = TestEnv::new(dec!(0.1));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.sell_escrowed(&owner, &id, dec!(100), 7);
+    let receipt_id = env.buy_escrowed(&buyers[0], &id, dec!(100));
+    let receipt = env.timeout_escrow(&buyers[1], &id);
+    expect_market_error(&receipt, 85); // EscrowNotOver
+    env.advance_time(7 * 24 * 3600);
+    env.timeout_escrow(&buyers[1], &id).expect_commit_success();
+    let result = env.claim_bid(&buyers[0], &receipt_id);
+    env.check_nft_received(&result, &buyers[0], &NonFungibleGlobalId::new(nft_addr, id));
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(90));
+}
+
+#[test]
+fn test_escrow_dispute_refunded_by_arbiter() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0.1));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.sell_escrowed(&owner, &id, dec!(100), 7);
+    let receipt_id = env.buy_escrowed(&buyers[0], &id, dec!(100));
+    let bid_addr = env.bid_addr;
+    env.dispute_escrow(&buyers[0], &id, &NonFungibleGlobalId::new(bid_addr, receipt_id.clone()));
+    env.advance_time(7 * 24 * 3600);
+    let receipt = env.timeout_escrow(&owner, &id);
+    expect_market_error(&receipt, 83); // EscrowDisputed
+    let other_badge = create_fungible_tokens(&mut env.runner, &buyers[1], dec!(1));
+    env.resolve_escrow(&buyers[1], other_badge, &id, true).expect_commit_failure();
+    env.resolve_escrow(&owner, fee_badge, &id, false).expect_commit_success();
+    let result = env.claim_bid(&buyers[0], &receipt_id);
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(100));
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Expired);
+    let receipt = env.buy_full(&buyers[1], &id, dec!(100), None, None, None, true);
+    expect_market_error(&receipt, 60); // ListingExpired
+    let receipt = env.cancel_intern(&owner, &badge, false);
+    env.check_nft_received(receipt.expect_commit_success(), &owner, &NonFungibleGlobalId::new(nft_addr, id));
+}
+
+#[test]
+fn test_migrate_populated_market() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0.1));
+    let gov_addr = create_fungible_tokens(&mut env.runner, &buyers[2], dec!(10));
+    env.set_staking(&owner, fee_badge, gov_addr, dec!(0.5));
+    let stake = env.stake(&buyers[2], gov_addr, dec!(10));
+    let active = env.sell(&owner, &NonFungibleLocalId::integer(1), dec!(10));
+    let sold = env.sell(&owner, &NonFungibleLocalId::integer(2), dec!(20));
+    env.buy(&buyers[0], &NonFungibleLocalId::integer(2), dec!(20));
+    let cancelled = env.sell(&owner, &NonFungibleLocalId::integer(3), dec!(30));
+    let old_instance = env.instance;
+    let receipt = env.migrate(&owner, fee_badge);
+    env.instance = receipt.expect_commit_success().new_component_addresses()[0];
+    assert_eq!(env.previous(&owner), Some(old_instance));
+    assert_eq!(env.listing_state(&owner, &sold), ListingState::Sold);
+    
+    env.buy(&buyers[1], &NonFungibleLocalId::integer(1), dec!(10));
+    let result = env.collect(&owner, &active);
+    env.check_balance_change(&result, &owner, XRD, dec!(9));
+    let result = env.collect(&owner, &sold);
+    env.check_balance_change(&result, &owner, XRD, dec!(18));
+    let receipt = env.cancel_intern(&owner, &cancelled, false);
+    env.check_nft_received(receipt.expect_commit_success(), &owner, &NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(3)));
+    assert_eq!(env.listing_state(&owner, &cancelled), ListingState::Cancelled);
+    env.sell(&owner, &NonFungibleLocalId::integer(3), dec!(30));
+    let result = env.call_stake_receipt(&buyers[2], "unstake", &stake);
+    env.check_balance_change(&result, &buyers[2], XRD, dec!(1.5));
+    let result = env.collect_fees(&owner, fee_badge);
+    env.check_balance_change(&result, &owner, XRD, dec!(1.5));
+}
+
+#[test]
+fn test_migrate_keeps_every_badge_and_receipt() {
+    let (mut env, owner, buyers, nft_addr, fee_badge, _) = TestEnv::new_with(dec!(0), false, |runner, seller|
+        create_non_fungible_tokens(runner, seller, [1,2,3,4,5,6,7].iter())
+    );
+    let nft = |i: u64| NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(i));
+    env.advance_time(0);
+    let offer = env.offer_loan(&buyers[0], dec!(100), dec!(5), 30);
+    let loan = env.borrow(&owner, &offer, &NonFungibleLocalId::integer(1));
+    let auction = env.sell_auction(&owner, &NonFungibleLocalId::integer(2), auction_terms(&env, dec!(5), None));
+    let auction_bid = env.bid(&buyers[1], &NonFungibleLocalId::integer(2), dec!(6));
+    env.sell_escrowed(&owner, &NonFungibleLocalId::integer(3), dec!(10), 7);
+    let escrow_receipt = env.buy_escrowed(&buyers[2], &NonFungibleLocalId::integer(3), dec!(10));
+    let layaway = env.sell(&owner, &NonFungibleLocalId::integer(4), dec!(30));
+    env.offer_layaway(&owner, &layaway, layaway_terms());
+    let layaway_receipt = env.start_layaway(&buyers[0], &NonFungibleLocalId::integer(4), dec!(10));
+    let pool = env.create_pool(&owner, &[5], dec!(0), dec!(10), Curve::Linear(dec!(1)), dec!(0));
+    let rental = env.list_rental(&owner, &NonFungibleLocalId::integer(6), dec!(1), 7);
+    env.rent(&buyers[1], &NonFungibleLocalId::integer(6), 2, dec!(2), false);
+    let share_addr = env.fractionalize(&owner, &NonFungibleLocalId::integer(7), dec!(10), dec!(50));
+    let receipt = env.migrate(&owner, fee_badge);
+    env.instance = receipt.expect_commit_success().new_component_addresses()[0];
+
+    let result = env.repay(&owner, &loan, dec!(105), false).expect_commit_success().clone();
+    env.check_nft_received(&result, &owner, &nft(1));
+    let result = env.collect_loan(&buyers[0], &offer, false).expect_commit_success().clone();
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(105));
+    let result = env.confirm_escrow(&buyers[2], &escrow_receipt);
+    env.check_nft_received(&result, &buyers[2], &nft(3));
+    env.pay_installment(&buyers[0], &layaway_receipt, dec!(10), false);
+    env.pay_installment(&buyers[0], &layaway_receipt, dec!(10), false);
+    let result = env.claim_bid(&buyers[0], &layaway_receipt);
+    env.check_nft_received(&result, &buyers[0], &nft(4));
+    let result = env.collect(&owner, &layaway);
+    env.check_balance_change(&result, &owner, XRD, dec!(30));
+    let result = env.buy_from_pool(&buyers[2], &pool, &NonFungibleLocalId::integer(5), dec!(10));
+    env.check_nft_received(&result, &buyers[2], &nft(5));
+    let result = env.close_pool(&owner, &pool);
+    env.check_balance_change(&result, &owner, XRD, dec!(10));
+    let result = env.buyout(&buyers[0], share_addr, dec!(50), false).expect_commit_success().clone();
+    env.check_nft_received(&result, &buyers[0], &nft(7));
+    let result = env.redeem_shares(&owner, share_addr, dec!(10), false).expect_commit_success().clone();
+    env.check_balance_change(&result, &owner, XRD, dec!(50));
+    let receipt = env.reclaim_rental(&owner, &rental, true);
+    expect_market_error(&receipt, 20); // RentalOngoing
+
+    env.advance_time(3 * 24 * 3600);
+    env.settle_auction(&owner, &NonFungibleLocalId::integer(2));
+    let result = env.claim_bid(&buyers[1], &auction_bid);
+    env.check_nft_received(&result, &buyers[1], &nft(2));
+    let result = env.collect(&owner, &auction);
+    env.check_balance_change(&result, &owner, XRD, dec!(6));
+    let result = env.reclaim_rental(&owner, &rental, false).expect_commit_success().clone();
+    env.check_nft_received(&result, &owner, &nft(6));
+    env.check_balance_change(&result, &owner, XRD, dec!(2));
+}
+
+#[test]
+fn test_migrate_keeps_swaps_bundles_tickets_and_trait_bids() {
+    let (mut env, owner, buyers, nft_addr, fee_badge, _) = TestEnv::new_with(dec!(0), false, |runner, seller|
+        create_trait_tokens(runner, seller, vec![(1, "gold", 1), (2, "gold", 1), (3, "gold", 1), (4, "gold", 1), (5, "gold", 1), (6, "blue", 5)])
+    );
+    let nft = |i: u64| NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(i));
+    env.advance_time(0);
+    let other_addr = create_non_fungible_tokens(&mut env.runner, &buyers[1], [9].iter());
+    let other = NonFungibleGlobalId::new(other_addr, NonFungibleLocalId::integer(9));
+    let swap = env.sell_swap(&owner, &NonFungibleLocalId::integer(1), SwapWant::Resource(other_addr), dec!(0));
+    env.fill_swap(&buyers[1], &NonFungibleLocalId::integer(1), &other, dec!(0), false);
+    let bundle = env.sell_bundle(&owner, &vec![nft(2), nft(3)], dec!(10));
+    let (bid_end, reveal_end) = (env.instant_in(3600), env.instant_in(7200));
+    let sealed = env.sell_sealed(&owner, &NonFungibleLocalId::integer(4), dec!(1), bid_end, reveal_end, false);
+    let sealed_bid = env.sealed_bid(&buyers[0], &NonFungibleLocalId::integer(4), dec!(8), "salt0", dec!(10));
+    let randomness = env.fixed_random(&owner, 0);
+    env.set_randomness(&owner, fee_badge, Some(randomness));
+    let deadline = env.instant_in(3600);
+    let raffle = env.sell_raffle(&owner, &NonFungibleLocalId::integer(5), dec!(3), 1, deadline);
+    let receipt = env.buy_tickets(&buyers[1], &NonFungibleLocalId::integer(5), 1, dec!(3), false);
+    let changes = env.runner.sum_descendant_balance_changes(receipt.expect_commit_success(), buyers[1].2.as_node_id());
+    let ticket = changes.get(&env.bid_addr).unwrap().clone().added_non_fungibles().iter().next().unwrap().clone();
+    env.set_trait_fields(&owner, fee_badge, vec!["background", "level"]);
+    let trait_bid = env.place_trait_bid(&buyers[2], dec!(7), vec![trait_constraint("background", "blue")]);
+    let receipt = env.migrate(&owner, fee_badge);
+    env.instance = receipt.expect_commit_success().new_component_addresses()[0];
+
+    let result = env.collect(&owner, &swap);
+    env.check_nft_received(&result, &owner, &other);
+    let receipt = env.buy_bundle(&buyers[0], &bundle, dec!(10), false);
+    env.check_nft_received(receipt.expect_commit_success(), &buyers[0], &nft(3));
+    let result = env.collect(&owner, &bundle);
+    env.check_balance_change(&result, &owner, XRD, dec!(10));
+    env.draw_raffle(&owner, &NonFungibleLocalId::integer(5), false);
+    let result = env.claim_bid(&buyers[1], &ticket);
+    env.check_nft_received(&result, &buyers[1], &nft(5));
+    let result = env.collect(&owner, &raffle);
+    env.check_balance_change(&result, &owner, XRD, dec!(3));
+    let receipt = env.fill_trait_bid(&owner, &trait_bid, &NonFungibleLocalId::integer(6), false);
+    env.check_balance_change(receipt.expect_commit_success(), &owner, XRD, dec!(7));
+    let result = env.claim_bid(&buyers[2], &trait_bid);
+    env.check_nft_received(&result, &buyers[2], &nft(6));
+    env.advance_time(3600);
+    env.reveal_bid(&buyers[0], &sealed_bid, dec!(8), "salt0", false);
+    env.advance_time(3600);
+    env.settle_sealed(&owner, &NonFungibleLocalId::integer(4));
+    let result = env.claim_bid(&buyers[0], &sealed_bid);
+    env.check_nft_received(&result, &buyers[0], &nft(4));
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(2));
+    let result = env.collect(&owner, &sealed);
+    env.check_balance_change(&result, &owner, XRD, dec!(8));
+}
+
+#[test]
+fn test_migrate_keeps_seller_rewards() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0));
+    let card_addr = create_non_fungible_tokens(&mut env.runner, &buyers[0], [1].iter());
+    let card = NonFungibleGlobalId::new(card_addr, NonFungibleLocalId::integer(1));
+    env.set_reward_rates(&owner, fee_badge, dec!(1), dec!(2), dec!(100));
+    let badge = env.sell(&owner, &NonFungibleLocalId::integer(1), dec!(5));
+    env.buy_as(&buyers[0], &NonFungibleLocalId::integer(1), dec!(5), &card);
+    let receipt = env.migrate(&owner, fee_badge);
+    env.instance = receipt.expect_commit_success().new_component_addresses()[0];
+    let reward_addr = env.reward_addr;
+    let result = env.collect(&owner, &badge);
+    env.check_balance_change(&result, &owner, reward_addr, dec!(10));
+    let result = env.claim_rewards(&buyers[0], &card);
+    env.check_balance_change(&result, &buyers[0], reward_addr, dec!(5));
+}
+
+#[test]
+fn test_migrated_market_is_retired() {
+    let (mut env, owner, _, _, fee_badge) = TestEnv::new(dec!(0));
+    let old_instance = env.instance;
+    env.migrate(&owner, fee_badge).expect_commit_success();
+    let receipt = env.migrate(&owner, fee_badge);
+    expect_market_error(&receipt, 87); // Migrated
+    let nft_addr = env.nft_addr;
+    env.instance = old_instance;
+    env.call_with_items(&owner, "sell", nft_addr, vec![1], Some(dec!(5))).expect_commit_failure();
+}
+
+#[test]
+fn test_wind_down_rescues_registered_listings() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0.1));
+    let listed = env.sell_registered(&owner, &NonFungibleLocalId::integer(1), dec!(10));
+    let sold = env.sell_registered(&owner, &NonFungibleLocalId::integer(2), dec!(20));
+    env.buy(&buyers[0], &NonFungibleLocalId::integer(2), dec!(20));
+    let unregistered = env.sell(&owner, &NonFungibleLocalId::integer(3), dec!(30));
+    env.wind_down(&owner, fee_badge);
+    let receipt = env.buy_full(&buyers[0], &NonFungibleLocalId::integer(3), dec!(30), None, None, None, true);
+    expect_market_error(&receipt, 88); // WindingDown
+    let receipt = env.rescue(&owner, fee_badge, 10);
+    let result = receipt.expect_commit_success();
+    let left: usize = result.output(1);
+    assert_eq!(left, 0);
+    env.check_nft_received(result, &owner, &NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(1)));
+    env.check_balance_change(result, &owner, XRD, dec!(18));
+    assert_eq!(env.listing_state(&owner, &listed), ListingState::Cancelled);
+    let badge_addr = env.badge_addr;
+    let receipt = env.call_with_ids(&owner, "collect", badge_addr, BTreeSet::from([sold]), None);
+    expect_market_error(&receipt, 10); // NothingToCollect
+    let receipt = env.cancel_intern(&owner, &unregistered, false);
+    env.check_nft_received(receipt.expect_commit_success(), &owner, &NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(3)));
+}
+
+#[test]
+fn test_rescue_by_batches() {
+    let (mut env, owner, _, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    for id in 1..=3 {
+        env.sell_registered(&owner, &NonFungibleLocalId::integer(id), dec!(10));
+    }
+    let receipt = env.rescue(&owner, fee_badge, 2);
+    expect_market_error(&receipt, 89); // NotWindingDown
+    env.wind_down(&owner, fee_badge);
+    let left: usize = env.rescue(&owner, fee_badge, 2).expect_commit_success().output(1);
+    assert_eq!(left, 1);
+    let left: usize = env.rescue(&owner, fee_badge, 2).expect_commit_success().output(1);
+    assert_eq!(left, 0);
+    let receipt = env.call_with_items(&owner, "sell", nft_addr, vec![1], Some(dec!(5)));
+    expect_market_error(&receipt, 88); // WindingDown
+}
+
+#[test]
+fn test_rescue_keeps_busy_listing_registered() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    env.advance_time(0);
+    let badge = env.sell_registered(&owner, &id, dec!(30));
+    env.offer_layaway(&owner, &badge, layaway_terms());
+    let receipt_id = env.start_layaway(&buyers[0], &id, dec!(10));
+    let items = vec![NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(2)), NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(3))];
+    let bundle = env.sell_bundle(&owner, &items, dec!(10));
+    env.wind_down(&owner, fee_badge);
+    let receipt = env.buy_bundle(&buyers[1], &bundle, dec!(10), true);
+    expect_market_error(&receipt, 88); // WindingDown
+    let left: usize = env.rescue(&owner, fee_badge, 10).expect_commit_success().output(1);
+    assert_eq!(left, 1);
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Active);
+    env.pay_installment(&buyers[0], &receipt_id, dec!(10), false);
+    env.pay_installment(&buyers[0], &receipt_id, dec!(10), false);
+    let receipt = env.rescue(&owner, fee_badge, 10);
+    let result = receipt.expect_commit_success();
+    let left: usize = result.output(1);
+    assert_eq!(left, 0);
+    env.check_balance_change(result, &owner, XRD, dec!(30));
+}
+
+#[test]
+fn test_rescue_counts_busy_listings() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0));
+    env.advance_time(0);
+    for id in 1..=2 {
+        let badge = env.sell_registered(&owner, &NonFungibleLocalId::integer(id), dec!(30));
+        env.offer_layaway(&owner, &badge, layaway_terms());
+        env.start_layaway(&buyers[0], &NonFungibleLocalId::integer(id), dec!(10));
+    }
+    env.sell_registered(&owner, &NonFungibleLocalId::integer(3), dec!(10));
+    env.wind_down(&owner, fee_badge);
+    // one listing examined per call, the idle one is reached within a round
+    let mut lefts = vec![];
+    for _ in 0..3 {
+        let left: usize = env.rescue(&owner, fee_badge, 1).expect_commit_success().output(1);
+        lefts.push(left);
+    }
+    assert!(lefts.windows(2).all(|pair| pair[0] >= pair[1]));
+    assert_eq!(lefts[2], 2);
+    let left: usize = env.rescue(&owner, fee_badge, 1).expect_commit_success().output(1);
+    assert_eq!(left, 2);
+}
+
+#[test]
+fn test_rescue_skips_refusing_account() {
+    let (mut env, owner, _, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let badge = env.sell_registered(&owner, &NonFungibleLocalId::integer(1), dec!(10));
+    env.sell_registered(&owner, &NonFungibleLocalId::integer(2), dec!(10));
+    env.wind_down(&owner, fee_badge);
+    env.set_deposit_rule(&owner, DefaultDepositRule::Reject);
+    let left: usize = env.rescue(&owner, fee_badge, 10).expect_commit_success().output(1);
+    assert_eq!(left, 2);
+    // not tried again once it accepts deposits, the badge still cancels
+    env.set_deposit_rule(&owner, DefaultDepositRule::Accept);
+    let left: usize = env.rescue(&owner, fee_badge, 10).expect_commit_success().output(1);
+    assert_eq!(left, 2);
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Active);
+    let receipt = env.cancel_intern(&owner, &badge, false);
+    env.check_nft_received(receipt.expect_commit_success(), &owner, &NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(1)));
+}
+
+#[test]
+fn test_wind_down_stops_pool_sales_loans_and_trait_bids() {
+    let (mut env, owner, buyers, _, fee_badge) = TestEnv::new(dec!(0));
+    let pool = env.create_pool(&owner, &[1], dec!(100), dec!(10), Curve::Linear(dec!(1)), dec!(0));
+    env.wind_down(&owner, fee_badge);
+    let receipt = env.sell_to_pool(&owner, &pool, &NonFungibleLocalId::integer(2), true);
+    expect_market_error(&receipt, 88); // WindingDown
+    let receipt = env.offer_loan_intern(&buyers[0], dec!(10), dec!(1), 7, true);
+    expect_market_error(&receipt, 88); // WindingDown
+    let receipt = env.place_trait_bid_intern(&buyers[0], dec!(10), vec![], true);
+    expect_market_error(&receipt, 88); // WindingDown
+}
+
+#[test]
+fn test_flagged_listing_frozen_and_seized() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let item = NonFungibleGlobalId::new(nft_addr, id.clone());
+    let badge = env.sell(&owner, &id, dec!(5));
+    let result = env.flag(&owner, fee_badge, "flag", &item);
+    env.check_event(&result, "FlaggedEvent");
+    assert!(env.is_flagged(&buyers[0], &item));
+    let receipt = env.buy_full(&buyers[0], &id, dec!(5), None, None, None, true);
+    expect_market_error(&receipt, 90); // Flagged
+    let receipt = env.cancel_intern(&owner, &badge, true);
+    expect_market_error(&receipt, 90); // Flagged
+    let result = env.seize_flagged(&owner, fee_badge, &item);
+    env.check_nft_received(&result, &owner, &item);
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Cancelled);
+    assert!(!env.is_flagged(&buyers[0], &item));
+}
+
+#[test]
+fn test_seize_flagged_auction_refunds_bidder() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let item = NonFungibleGlobalId::new(nft_addr, id.clone());
+    env.advance_time(0);
+    let badge = env.sell_auction(&owner, &id, auction_terms(&env, dec!(5), None));
+    let bid = env.bid(&buyers[0], &id, dec!(6));
+    env.flag(&owner, fee_badge, "flag", &item);
+    env.bid_fail(&buyers[1], &id, dec!(7));
+    let result = env.seize_flagged(&owner, fee_badge, &item);
+    env.check_nft_received(&result, &owner, &item);
+    assert_eq!(env.listing_state(&owner, &badge), ListingState::Cancelled);
+    let result = env.claim_bid(&buyers[0], &bid);
+    env.check_balance_change(&result, &buyers[0], XRD, dec!(6));
+}
+
+#[test]
+fn test_seize_flagged_bundle_item() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let items = vec![NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(1)), NonFungibleGlobalId::new(nft_addr, NonFungibleLocalId::integer(2))];
+    let bundle = env.sell_bundle(&owner, &items, dec!(10));
+    env.flag(&owner, fee_badge, "flag", &items[1]);
+    let receipt = env.buy_bundle(&buyers[0], &bundle, dec!(10), true);
+    expect_market_error(&receipt, 90); // Flagged
+    let receipt = env.cancel_intern(&owner, &bundle, true);
+    expect_market_error(&receipt, 90); // Flagged
+    let result = env.seize_flagged(&owner, fee_badge, &items[1]);
+    env.check_nft_received(&result, &owner, &items[0]);
+    env.check_nft_received(&result, &owner, &items[1]);
+    assert_eq!(env.listing_state(&owner, &bundle), ListingState::Cancelled);
+}
+
+#[test]
+fn test_flagged_layaway_frozen() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let item = NonFungibleGlobalId::new(nft_addr, id.clone());
+    env.advance_time(0);
+    let badge = env.sell(&owner, &id, dec!(30));
+    env.offer_layaway(&owner, &badge, layaway_terms());
+    let receipt_id = env.start_layaway(&buyers[0], &id, dec!(10));
+    env.flag(&owner, fee_badge, "flag", &item);
+    let receipt = env.pay_installment(&buyers[0], &receipt_id, dec!(10), true);
+    expect_market_error(&receipt, 90); // Flagged
+    env.flag(&owner, fee_badge, "unflag", &item);
+    env.pay_installment(&buyers[0], &receipt_id, dec!(10), false);
+}
+
+#[test]
+fn test_flagged_escrow_frozen() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(1);
+    let item = NonFungibleGlobalId::new(nft_addr, id.clone());
+    env.advance_time(0);
+    env.sell_escrowed(&owner, &id, dec!(100), 7);
+    let receipt_id = env.buy_escrowed(&buyers[0], &id, dec!(100));
+    env.flag(&owner, fee_badge, "flag", &item);
+    let receipt = env.confirm_escrow_intern(&buyers[0], &receipt_id, true);
+    expect_market_error(&receipt, 90); // Flagged
+    env.advance_time(7 * 24 * 3600);
+    let receipt = env.timeout_escrow(&buyers[1], &id);
+    expect_market_error(&receipt, 90); // Flagged
+}
+
+#[test]
+fn test_flagged_nft_cannot_be_listed() {
+    let (mut env, owner, buyers, nft_addr, fee_badge) = TestEnv::new(dec!(0));
+    let id = NonFungibleLocalId::integer(2);
+    let item = NonFungibleGlobalId::new(nft_addr, id.clone());
+    env.flag(&owner, fee_badge, "flag", &item);
+    let receipt = env.call_with_items(&owner, "sell", nft_addr, vec![2], Some(dec!(5)));
+    expect_market_error(&receipt, 90); // Flagged
+    env.flag(&owner, fee_badge, "unflag", &item);
+    env.sell(&owner, &id, dec!(5));
+    env.buy(&buyers[0], &id, dec!(5));
+}